@@ -0,0 +1,60 @@
+use std::{
+    os::fd::{FromRawFd, OwnedFd},
+    sync::Arc,
+};
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use futures::lock::Mutex;
+use way_too_far::{
+    accounting::ClientUsage, texture::Texture, wl_buffer::BufferState, wl_shm::WlShmFormat,
+    wl_shm_pool::ShmPool,
+};
+
+mod common;
+use common::pool_fd;
+
+const WIDTH: i32 = 1920;
+const HEIGHT: i32 = 1080;
+
+fn bench_upload(c: &mut Criterion, name: &str, format: WlShmFormat, bytes_per_pixel: i32) {
+    let stride = WIDTH * bytes_per_pixel;
+    let size = (stride * HEIGHT) as usize;
+    let fd = unsafe { OwnedFd::from_raw_fd(pool_fd(size)) };
+    let usage = Arc::new(ClientUsage::new(None));
+    let pool = Arc::new(Mutex::new(ShmPool::new(fd, size, usage.clone()).unwrap()));
+    let buffer = BufferState {
+        offset: 0,
+        width: WIDTH,
+        height: HEIGHT,
+        stride,
+        format: format as u32,
+        shm_pool: pool.clone(),
+        usage,
+    };
+    let pool = pool.try_lock().unwrap();
+    let mut texture = Texture::default();
+    let full = [(0, 0, WIDTH, HEIGHT)];
+    pool.access(|bytes| texture.upload(&buffer, bytes, &full))
+        .unwrap()
+        .unwrap();
+
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Bytes(size as u64));
+    group.bench_function("full_frame", |b| {
+        b.iter(|| {
+            pool.access(|bytes| texture.upload(&buffer, bytes, &full))
+                .unwrap()
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+fn bench_blits(c: &mut Criterion) {
+    bench_upload(c, "upload_argb8888", WlShmFormat::Argb8888, 4);
+    bench_upload(c, "upload_xrgb8888", WlShmFormat::Xrgb8888, 4);
+    bench_upload(c, "upload_rgb888", WlShmFormat::Rgb888, 3);
+}
+
+criterion_group!(benches, bench_blits);
+criterion_main!(benches);
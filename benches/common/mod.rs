@@ -0,0 +1,126 @@
+#![allow(dead_code)]
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, RwLock},
+};
+
+use tokio::{io::AsyncReadExt, net::UnixStream, runtime::Runtime};
+use way_too_far::{CompositorClientState, CompositorGlobalState, utils::get_wayland_string_bytes};
+
+pub const SHM_ID: u32 = 3;
+pub const COMPOSITOR_ID: u32 = 4;
+pub const POOL_ID: u32 = 5;
+pub const BUFFER_ID: u32 = 6;
+pub const SURFACE_ID: u32 = 7;
+
+pub fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+pub fn global_state() -> Arc<RwLock<CompositorGlobalState>> {
+    Arc::new(RwLock::new(CompositorGlobalState::default()))
+}
+
+/// A client socket whose peer is drained so queued events never back up.
+pub fn client_stream(rt: &Runtime) -> UnixStream {
+    rt.block_on(async {
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        tokio::spawn(async move {
+            let mut sink = vec![0u8; 64 * 1024];
+            while let Ok(read) = peer.read(&mut sink).await {
+                if read == 0 {
+                    break;
+                }
+            }
+        });
+        stream
+    })
+}
+
+pub fn args(values: &[u32]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+}
+
+pub fn bind_args(name: u32, interface: &str, version: u32, new_id: u32) -> Vec<u8> {
+    let mut args = name.to_le_bytes().to_vec();
+    args.extend_from_slice(&get_wayland_string_bytes(interface));
+    args.extend_from_slice(&version.to_le_bytes());
+    args.extend_from_slice(&new_id.to_le_bytes());
+    args
+}
+
+/// Creates a memfd of `size` bytes filled with a repeating byte pattern.
+pub fn pool_fd(size: usize) -> i32 {
+    unsafe {
+        let fd = libc::memfd_create(c"bench-pool".as_ptr(), 0);
+        assert!(fd >= 0 && libc::ftruncate(fd, size as libc::off_t) == 0);
+        let pattern = (0..size).map(|i| i as u8).collect::<Vec<u8>>();
+        assert_eq!(
+            libc::pwrite(fd, pattern.as_ptr().cast(), size, 0),
+            size as isize
+        );
+        fd
+    }
+}
+
+pub async fn dispatch(
+    client: &mut CompositorClientState<'_>,
+    object_id: u32,
+    op_code: u16,
+    args: &[u8],
+) {
+    client
+        .handle_message(object_id, op_code, args, &mut VecDeque::new())
+        .await
+        .unwrap();
+}
+
+/// Binds wl_shm and wl_compositor and maps a `width` x `height` surface with one buffer.
+pub async fn setup_surface(
+    client: &mut CompositorClientState<'_>,
+    width: i32,
+    height: i32,
+    format: u32,
+    bytes_per_pixel: i32,
+) {
+    dispatch(client, 1, 1, &args(&[2])).await;
+    dispatch(client, 2, 0, &bind_args(1, "wl_shm", 1, SHM_ID)).await;
+    dispatch(
+        client,
+        2,
+        0,
+        &bind_args(2, "wl_compositor", 6, COMPOSITOR_ID),
+    )
+    .await;
+
+    let stride = width * bytes_per_pixel;
+    let size = (stride * height) as usize;
+    let mut fds = VecDeque::from([pool_fd(size)]);
+    client
+        .handle_message(SHM_ID, 0, &args(&[POOL_ID, size as u32]), &mut fds)
+        .await
+        .unwrap();
+    dispatch(
+        client,
+        POOL_ID,
+        0,
+        &args(&[
+            BUFFER_ID,
+            0,
+            width as u32,
+            height as u32,
+            stride as u32,
+            format,
+        ]),
+    )
+    .await;
+    dispatch(client, COMPOSITOR_ID, 0, &args(&[SURFACE_ID])).await;
+    client.flush().await.unwrap();
+}
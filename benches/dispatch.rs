@@ -0,0 +1,78 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use way_too_far::{CompositorClientState, wl_shm::WlShmFormat};
+
+mod common;
+use common::*;
+
+const WIDTH: i32 = 1920;
+const HEIGHT: i32 = 1080;
+
+fn bench_registry(c: &mut Criterion) {
+    let rt = runtime();
+    let globals = global_state();
+    let mut stream = client_stream(&rt);
+
+    c.bench_function("registry_get_and_bind_all", |b| {
+        b.iter(|| {
+            let mut client = CompositorClientState::new(&mut stream, globals.clone());
+            rt.block_on(async {
+                dispatch(&mut client, 1, 1, &args(&[2])).await;
+                dispatch(&mut client, 2, 0, &bind_args(1, "wl_shm", 1, 3)).await;
+                dispatch(&mut client, 2, 0, &bind_args(2, "wl_compositor", 6, 4)).await;
+                dispatch(&mut client, 2, 0, &bind_args(3, "xdg_wm_base", 7, 5)).await;
+                dispatch(&mut client, 1, 0, &args(&[6])).await;
+                client.flush().await.unwrap();
+            })
+        })
+    });
+}
+
+/// Attaches, damages the given buffer rectangles and commits, as one frame.
+fn bench_frames(c: &mut Criterion, name: &str, damage: &[(i32, i32, i32, i32)]) {
+    let rt = runtime();
+    let globals = global_state();
+    let mut stream = client_stream(&rt);
+    let mut client = CompositorClientState::new(&mut stream, globals);
+    rt.block_on(setup_surface(
+        &mut client,
+        WIDTH,
+        HEIGHT,
+        WlShmFormat::Argb8888 as u32,
+        4,
+    ));
+    let damage = damage
+        .iter()
+        .map(|&(x, y, w, h)| args(&[x as u32, y as u32, w as u32, h as u32]))
+        .collect::<Vec<_>>();
+
+    c.bench_function(name, |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                dispatch(&mut client, SURFACE_ID, 1, &args(&[BUFFER_ID, 0, 0])).await;
+                for rect in &damage {
+                    dispatch(&mut client, SURFACE_ID, 9, rect).await;
+                }
+                dispatch(&mut client, SURFACE_ID, 6, &[]).await;
+                client.flush().await.unwrap();
+            })
+        })
+    });
+}
+
+fn bench_commit(c: &mut Criterion) {
+    // A terminal redrawing its cursor line and the status line
+    bench_frames(
+        c,
+        "commit_terminal_redraw",
+        &[
+            (0, 540, WIDTH, 18),
+            (12, 558, 9, 18),
+            (0, HEIGHT - 18, WIDTH, 18),
+        ],
+    );
+    // Video playback damages the whole buffer every frame
+    bench_frames(c, "commit_video_playback", &[(0, 0, WIDTH, HEIGHT)]);
+}
+
+criterion_group!(benches, bench_registry, bench_commit);
+criterion_main!(benches);
@@ -0,0 +1,81 @@
+use std::collections::VecDeque;
+
+use bytes::BytesMut;
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use std::hint::black_box;
+
+use way_too_far::wire;
+
+/// A stream of wl_surface.damage_buffer + commit pairs, the shape of a busy client.
+fn damage_commit_stream(frames: usize) -> Vec<u8> {
+    let mut stream = Vec::new();
+    for frame in 0..frames as i32 {
+        stream.extend_from_slice(&3u32.to_le_bytes());
+        stream.extend_from_slice(&9u16.to_le_bytes());
+        stream.extend_from_slice(&24u16.to_le_bytes());
+        for arg in [frame % 640, frame % 480, 64, 16] {
+            stream.extend_from_slice(&arg.to_le_bytes());
+        }
+        stream.extend_from_slice(&3u32.to_le_bytes());
+        stream.extend_from_slice(&6u16.to_le_bytes());
+        stream.extend_from_slice(&8u16.to_le_bytes());
+    }
+    stream
+}
+
+/// The previous read path: bytes pushed through a VecDeque and copied into an args vec.
+fn decode_vecdeque(input: &[u8]) -> usize {
+    let mut data = VecDeque::<u8>::new();
+    let mut total = 0;
+    for chunk in input.chunks(4096) {
+        for byte in chunk {
+            data.push_back(*byte);
+        }
+        while data.len() >= 8 && data.len() >= u16::from_le_bytes([data[6], data[7]]) as usize {
+            let object_id = u32::from_le_bytes([
+                data.pop_front().unwrap(),
+                data.pop_front().unwrap(),
+                data.pop_front().unwrap(),
+                data.pop_front().unwrap(),
+            ]);
+            let _op_code =
+                u16::from_le_bytes([data.pop_front().unwrap(), data.pop_front().unwrap()]);
+            let message_length =
+                u16::from_le_bytes([data.pop_front().unwrap(), data.pop_front().unwrap()]);
+            let mut args_buffer = vec![0u8; message_length as usize - 8];
+            (0..args_buffer.len()).for_each(|i| {
+                args_buffer[i] = data.pop_front().unwrap();
+            });
+            total += object_id as usize + black_box(&args_buffer).len();
+        }
+    }
+    total
+}
+
+fn decode_bytes_mut(input: &[u8]) -> usize {
+    let mut data = BytesMut::with_capacity(4096);
+    let mut total = 0;
+    for chunk in input.chunks(4096) {
+        data.extend_from_slice(chunk);
+        while let Some(message) = wire::decode_message(&mut data).unwrap() {
+            total += message.object_id as usize + black_box(&message.args[..]).len();
+        }
+    }
+    total
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let input = damage_commit_stream(10_000);
+    let mut group = c.benchmark_group("decode_damage_commit");
+    group.throughput(Throughput::Bytes(input.len() as u64));
+    group.bench_function("vecdeque", |b| {
+        b.iter(|| decode_vecdeque(black_box(&input)))
+    });
+    group.bench_function("bytes_mut", |b| {
+        b.iter(|| decode_bytes_mut(black_box(&input)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_decode);
+criterion_main!(benches);
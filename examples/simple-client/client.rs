@@ -0,0 +1,159 @@
+//! The client half of the `simple-client` example, shared with the
+//! integration tests.
+
+use memmap2::MmapMut;
+use std::os::fd::AsFd;
+use wayland_client::{
+    Connection, Dispatch, QueueHandle, delegate_noop,
+    globals::{GlobalListContents, registry_queue_init},
+    protocol::{
+        wl_buffer, wl_callback, wl_compositor, wl_registry, wl_shm, wl_shm_pool, wl_surface,
+    },
+};
+
+pub const WIDTH: i32 = 256;
+pub const HEIGHT: i32 = 256;
+const STRIDE: i32 = WIDTH * 4;
+const BUFFER_SIZE: i32 = STRIDE * HEIGHT;
+/// Two buffers, so one can be drawn while the other is still attached.
+const BUFFER_COUNT: usize = 2;
+
+/// What the compositor sent back while the client ran.
+#[derive(Debug, Default)]
+pub struct FrameStats {
+    /// Callback data of every frame callback that fired.
+    pub frames_done: Vec<u32>,
+    pub buffers_released: usize,
+}
+
+struct State {
+    stats: FrameStats,
+    busy: [bool; BUFFER_COUNT],
+    frame_pending: bool,
+}
+
+/// Draws `frames` frames of a scrolling gradient to a new surface, waiting
+/// for each frame callback before drawing the next.
+pub fn run(conn: &Connection, frames: usize) -> anyhow::Result<FrameStats> {
+    let (globals, mut queue) = registry_queue_init::<State>(conn)?;
+    let qh = queue.handle();
+    let shm: wl_shm::WlShm = globals.bind(&qh, 1..=1, ())?;
+    let compositor: wl_compositor::WlCompositor = globals.bind(&qh, 1..=6, ())?;
+
+    // One pool holds both buffers back to back
+    let file = tempfile::tempfile()?;
+    file.set_len((BUFFER_SIZE as usize * BUFFER_COUNT) as u64)?;
+    let mut pixels = unsafe { MmapMut::map_mut(&file)? };
+    let pool = shm.create_pool(file.as_fd(), BUFFER_SIZE * BUFFER_COUNT as i32, &qh, ());
+    let buffers = (0..BUFFER_COUNT)
+        .map(|index| {
+            pool.create_buffer(
+                index as i32 * BUFFER_SIZE,
+                WIDTH,
+                HEIGHT,
+                STRIDE,
+                wl_shm::Format::Xrgb8888,
+                &qh,
+                index,
+            )
+        })
+        .collect::<Vec<_>>();
+    let surface = compositor.create_surface(&qh, ());
+
+    let mut state = State {
+        stats: FrameStats::default(),
+        busy: [false; BUFFER_COUNT],
+        frame_pending: false,
+    };
+    for frame in 0..frames {
+        let index = loop {
+            if let Some(index) = state.busy.iter().position(|busy| !busy) {
+                break index;
+            }
+            queue.blocking_dispatch(&mut state)?;
+        };
+        let start = index * BUFFER_SIZE as usize;
+        draw_gradient(&mut pixels[start..start + BUFFER_SIZE as usize], frame);
+
+        surface.attach(Some(&buffers[index]), 0, 0);
+        surface.damage_buffer(0, 0, WIDTH, HEIGHT);
+        surface.frame(&qh, ());
+        surface.commit();
+        state.busy[index] = true;
+        state.frame_pending = true;
+        while state.frame_pending {
+            queue.blocking_dispatch(&mut state)?;
+        }
+    }
+
+    // Collect releases for the last frame
+    queue.roundtrip(&mut state)?;
+    for buffer in buffers {
+        buffer.destroy();
+    }
+    pool.destroy();
+    surface.destroy();
+    conn.flush()?;
+    Ok(state.stats)
+}
+
+/// A diagonal gradient that shifts by one pixel each frame.
+fn draw_gradient(pixels: &mut [u8], frame: usize) {
+    for (i, pixel) in pixels.chunks_exact_mut(4).enumerate() {
+        let x = (i % WIDTH as usize + frame) as u32;
+        let y = (i / WIDTH as usize) as u32;
+        let red = x * 255 / WIDTH as u32 % 256;
+        let green = y * 255 / HEIGHT as u32;
+        let blue = 255 - red;
+        pixel.copy_from_slice(&(red << 16 | green << 8 | blue).to_le_bytes());
+    }
+}
+
+delegate_noop!(State: ignore wl_compositor::WlCompositor);
+delegate_noop!(State: ignore wl_shm::WlShm);
+delegate_noop!(State: ignore wl_shm_pool::WlShmPool);
+delegate_noop!(State: ignore wl_surface::WlSurface);
+
+impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for State {
+    fn event(
+        _state: &mut Self,
+        _registry: &wl_registry::WlRegistry,
+        _event: wl_registry::Event,
+        _data: &GlobalListContents,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<wl_callback::WlCallback, ()> for State {
+    fn event(
+        state: &mut Self,
+        _callback: &wl_callback::WlCallback,
+        event: wl_callback::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if let wl_callback::Event::Done { callback_data } = event {
+            state.stats.frames_done.push(callback_data);
+            state.frame_pending = false;
+        }
+    }
+}
+
+impl Dispatch<wl_buffer::WlBuffer, usize> for State {
+    fn event(
+        state: &mut Self,
+        _buffer: &wl_buffer::WlBuffer,
+        event: wl_buffer::Event,
+        index: &usize,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if let wl_buffer::Event::Release = event {
+            state.busy[*index] = false;
+            state.stats.buffers_released += 1;
+        }
+    }
+}
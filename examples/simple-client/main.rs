@@ -0,0 +1,29 @@
+//! A minimal client: binds wl_shm and wl_compositor, draws a scrolling
+//! gradient into shm buffers and paces itself with frame callbacks.
+//!
+//! Start the compositor, then run
+//!
+//! ```text
+//! WAYLAND_DISPLAY=/tmp/my-wayland-socket.sock cargo run --example simple-client [frames]
+//! ```
+
+mod client;
+
+use wayland_client::Connection;
+
+fn main() -> anyhow::Result<()> {
+    let frames = match std::env::args().nth(1) {
+        Some(frames) => frames
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid frame count {}: {}", frames, e))?,
+        None => 60,
+    };
+    let conn = Connection::connect_to_env()?;
+    let stats = client::run(&conn, frames)?;
+    println!(
+        "{} frames done, {} buffer releases",
+        stats.frames_done.len(),
+        stats.buffers_released
+    );
+    Ok(())
+}
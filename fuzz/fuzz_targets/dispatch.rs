@@ -0,0 +1,64 @@
+//! Dispatches an arbitrary request stream, with shm fds attached, to a fresh
+//! client.
+//!
+//! Handlers may reject the input with an error but must never panic.
+
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use std::{
+    collections::VecDeque,
+    os::fd::{FromRawFd, OwnedFd},
+    sync::{Arc, LazyLock, RwLock},
+};
+use tokio::{net::UnixStream, runtime::Runtime};
+use way_too_far::{CompositorClientState, CompositorGlobalState, wire};
+
+const POOL_SIZE: i64 = 64 * 1024;
+
+static RUNTIME: LazyLock<Runtime> = LazyLock::new(|| {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+});
+
+/// A memfd large enough for small pools, as a client would send.
+fn shm_fd() -> i32 {
+    unsafe {
+        let fd = libc::memfd_create(c"fuzz-pool".as_ptr(), libc::MFD_CLOEXEC);
+        assert!(fd >= 0);
+        assert_eq!(libc::ftruncate(fd, POOL_SIZE), 0);
+        fd
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&fd_count, stream)) = data.split_first() else {
+        return;
+    };
+    let mut fds = (0..fd_count % 4).map(|_| shm_fd()).collect::<VecDeque<_>>();
+
+    RUNTIME.block_on(async {
+        // Events are never read back; inputs are small enough that they fit
+        // in the socket buffer whenever the client state flushes
+        let (mut server, _client) = UnixStream::pair().unwrap();
+        let global_state = Arc::new(RwLock::new(CompositorGlobalState::default()));
+        let mut client_state = CompositorClientState::new(&mut server, global_state);
+
+        let mut buffer = BytesMut::from(stream);
+        while let Ok(Some(message)) = wire::decode_message(&mut buffer) {
+            let result = client_state
+                .handle_message(message.object_id, message.op_code, &message.args, &mut fds)
+                .await;
+            if result.is_err() {
+                break;
+            }
+        }
+    });
+
+    for fd in fds {
+        drop(unsafe { OwnedFd::from_raw_fd(fd) });
+    }
+});
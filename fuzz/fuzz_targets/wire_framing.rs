@@ -0,0 +1,44 @@
+//! Splits an arbitrary byte stream into messages and reads their arguments.
+
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use way_too_far::wire::{self, ArgReader};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&chunk_size, stream)) = data.split_first() else {
+        return;
+    };
+    // Arrive in chunks, as reads from the socket do, so messages straddle
+    // reads
+    let chunk_size = chunk_size.max(1) as usize;
+
+    let mut buffer = BytesMut::new();
+    let mut framed = 0;
+    for chunk in stream.chunks(chunk_size) {
+        buffer.extend_from_slice(chunk);
+        loop {
+            let message = match wire::decode_message(&mut buffer) {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(_) => return,
+            };
+            framed += wire::HEADER_SIZE + message.args.len();
+
+            // Let the opcode pick a signature, one bit per argument
+            let mut args = ArgReader::new(&message.args);
+            for bit in 0..16 {
+                let read = if message.op_code & (1 << bit) != 0 {
+                    args.string().map(drop)
+                } else {
+                    args.uint().map(drop)
+                };
+                if read.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    assert_eq!(framed + buffer.len(), stream.len());
+});
@@ -0,0 +1,199 @@
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        Mutex, PoisonError,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/// Length of the window commit rates are measured over.
+const COMMIT_WINDOW: Duration = Duration::from_secs(1);
+
+/// Resources held by one client, shared with the IPC server for reporting.
+#[derive(Default)]
+pub struct ClientUsage {
+    pid: Option<i32>,
+    shm_bytes: AtomicUsize,
+    buffers: AtomicUsize,
+    objects: AtomicUsize,
+    messages: AtomicU64,
+    /// Left an xdg_wm_base ping unanswered past the ping timeout.
+    unresponsive: AtomicBool,
+    /// Commit timing per surface id.
+    surfaces: Mutex<HashMap<u32, CommitTiming>>,
+}
+
+/// Commit timing of one surface, counted in one-second windows.
+#[derive(Default)]
+struct CommitTiming {
+    commits: u64,
+    last_commit: Option<Instant>,
+    window_start: Option<Instant>,
+    window: WindowStats,
+    /// The most recently completed window.
+    last_window: WindowStats,
+}
+
+#[derive(Default, Clone, Copy)]
+struct WindowStats {
+    commits: u32,
+    longest_gap: Duration,
+}
+
+impl CommitTiming {
+    fn record(&mut self, now: Instant) {
+        let window_start = *self.window_start.get_or_insert(now);
+        if now.duration_since(window_start) >= COMMIT_WINDOW {
+            self.last_window = self.window;
+            self.window = WindowStats::default();
+            self.window_start = Some(now);
+        }
+        if let Some(last_commit) = self.last_commit {
+            self.window.longest_gap = self.window.longest_gap.max(now - last_commit);
+        }
+        self.window.commits += 1;
+        self.commits += 1;
+        self.last_commit = Some(now);
+    }
+
+    /// The latest complete window, or an empty one if the surface has been
+    /// idle for longer than that.
+    fn latest_window(&self, now: Instant) -> WindowStats {
+        match self.window_start.map(|start| now.duration_since(start)) {
+            Some(age) if age < COMMIT_WINDOW => self.last_window,
+            Some(age) if age < 2 * COMMIT_WINDOW => self.window,
+            _ => WindowStats::default(),
+        }
+    }
+}
+
+/// Commit statistics of one surface, as reported over IPC.
+#[derive(Debug, Serialize)]
+pub struct SurfaceStats {
+    pub client_id: u64,
+    pub surface_id: u32,
+    pub commits: u64,
+    /// Commits in the last complete second.
+    pub commits_last_second: u32,
+    /// Longest time between two commits in that second, a sign of jank for
+    /// a surface that should be animating smoothly.
+    pub longest_gap_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageSnapshot {
+    pub client_id: u64,
+    pub pid: Option<i32>,
+    pub shm_bytes: usize,
+    pub buffers: usize,
+    pub objects: usize,
+    pub messages: u64,
+    pub unresponsive: bool,
+}
+
+impl ClientUsage {
+    pub fn new(pid: Option<i32>) -> Self {
+        ClientUsage {
+            pid,
+            ..Default::default()
+        }
+    }
+
+    pub fn pid(&self) -> Option<i32> {
+        self.pid
+    }
+
+    pub fn shm_bytes(&self) -> usize {
+        self.shm_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn add_shm_bytes(&self, bytes: usize) {
+        self.shm_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn remove_shm_bytes(&self, bytes: usize) {
+        self.shm_bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub fn buffers(&self) -> usize {
+        self.buffers.load(Ordering::Relaxed)
+    }
+
+    pub fn add_buffer(&self) {
+        self.buffers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn remove_buffer(&self) {
+        self.buffers.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn objects(&self) -> usize {
+        self.objects.load(Ordering::Relaxed)
+    }
+
+    pub fn set_objects(&self, objects: usize) {
+        self.objects.store(objects, Ordering::Relaxed);
+    }
+
+    pub fn messages(&self) -> u64 {
+        self.messages.load(Ordering::Relaxed)
+    }
+
+    pub fn record_message(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn unresponsive(&self) -> bool {
+        self.unresponsive.load(Ordering::Relaxed)
+    }
+
+    pub fn set_unresponsive(&self, unresponsive: bool) {
+        self.unresponsive.store(unresponsive, Ordering::Relaxed);
+    }
+
+    pub fn record_commit(&self, surface_id: u32) {
+        let mut surfaces = self.surfaces.lock().unwrap_or_else(PoisonError::into_inner);
+        surfaces
+            .entry(surface_id)
+            .or_default()
+            .record(Instant::now());
+    }
+
+    pub fn remove_surface(&self, surface_id: u32) {
+        let mut surfaces = self.surfaces.lock().unwrap_or_else(PoisonError::into_inner);
+        surfaces.remove(&surface_id);
+    }
+
+    /// Commit statistics of every surface that has committed.
+    pub fn surface_stats(&self, client_id: u64) -> Vec<SurfaceStats> {
+        let now = Instant::now();
+        let surfaces = self.surfaces.lock().unwrap_or_else(PoisonError::into_inner);
+        surfaces
+            .iter()
+            .map(|(&surface_id, timing)| {
+                let window = timing.latest_window(now);
+                SurfaceStats {
+                    client_id,
+                    surface_id,
+                    commits: timing.commits,
+                    commits_last_second: window.commits,
+                    longest_gap_ms: window.longest_gap.as_secs_f64() * 1000.0,
+                }
+            })
+            .collect()
+    }
+
+    pub fn snapshot(&self, client_id: u64) -> UsageSnapshot {
+        UsageSnapshot {
+            client_id,
+            pid: self.pid,
+            shm_bytes: self.shm_bytes(),
+            buffers: self.buffers(),
+            objects: self.objects(),
+            messages: self.messages(),
+            unresponsive: self.unresponsive(),
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use crate::{
+    CompositorGlobalState,
+    config::{ExecConfig, SessionConfig},
+    serve_client, session,
+};
+use std::{
+    os::{fd::AsRawFd, unix::net::UnixStream as StdUnixStream},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{net::UnixStream, process::Command};
+use tracing::{info, warn};
+
+/// Pause before restarting a command that exited, so one that fails
+/// immediately doesn't spin.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Runs one configured command, restarting it whenever it exits if it is
+/// marked `restart`.
+pub async fn supervise(
+    exec: ExecConfig,
+    session: SessionConfig,
+    socket_path: PathBuf,
+    global_state: Arc<RwLock<CompositorGlobalState>>,
+) {
+    loop {
+        let mut child = match spawn(&exec, &session, &socket_path, &global_state) {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Failed to start {:?}: {}", exec.command, e);
+                return;
+            }
+        };
+        let pid = child.id().unwrap_or_default();
+        info!("Started {:?} as pid {}", exec.command, pid);
+
+        let status = match child.wait().await {
+            Ok(status) => status.to_string(),
+            Err(e) => e.to_string(),
+        };
+        if !exec.restart {
+            info!("{:?} (pid {}) exited: {}", exec.command, pid, status);
+            return;
+        }
+        warn!(
+            "{:?} (pid {}) exited: {}, restarting",
+            exec.command, pid, status
+        );
+        tokio::time::sleep(RESTART_DELAY).await;
+    }
+}
+
+fn spawn(
+    exec: &ExecConfig,
+    session: &SessionConfig,
+    socket_path: &Path,
+    global_state: &Arc<RwLock<CompositorGlobalState>>,
+) -> anyhow::Result<tokio::process::Child> {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(&exec.command);
+    for variable in session::client_environment(session, socket_path) {
+        if let Some((name, value)) = variable.split_once('=') {
+            command.env(name, value);
+        }
+    }
+    if !exec.wayland_socket {
+        return Ok(command.spawn()?);
+    }
+
+    // The child gets its end of a connected pair; ours is served like any
+    // accepted client
+    let (ours, theirs) = StdUnixStream::pair()?;
+    let fd = theirs.as_raw_fd();
+    command.env_remove("WAYLAND_DISPLAY");
+    command.env("WAYLAND_SOCKET", fd.to_string());
+    // Sockets are created close-on-exec; clearing the flag after the fork
+    // only affects the child's copy
+    unsafe {
+        command.pre_exec(move || {
+            if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn()?;
+    drop(theirs);
+
+    ours.set_nonblocking(true)?;
+    tokio::spawn(serve_client(
+        UnixStream::from_std(ours)?,
+        global_state.clone(),
+    ));
+    Ok(child)
+}
@@ -0,0 +1,308 @@
+//! Protocol capture files and their offline replay.
+//!
+//! A capture is one JSON object per line, in the order the compositor saw
+//! them: requests as they were decoded, fds as they arrived and events as
+//! they were written to the socket. File descriptors can't be saved, so only
+//! their type and size are recorded; replay stands in a temporary file of
+//! that size.
+
+use crate::{CompositorClientState, CompositorGlobalState, wire};
+use bytes::BytesMut;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    os::fd::{AsRawFd, IntoRawFd, RawFd},
+    path::Path,
+    sync::{Arc, RwLock},
+    time::Instant,
+};
+use tokio::{io::AsyncReadExt, net::UnixStream};
+use tracing::{debug, warn};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CaptureRecord {
+    Request {
+        time_us: u64,
+        interface: String,
+        object_id: u32,
+        op_code: u16,
+        /// Argument bytes, hex-encoded.
+        args: String,
+    },
+    Fds {
+        time_us: u64,
+        fds: Vec<FdInfo>,
+    },
+    Event {
+        time_us: u64,
+        object_id: u32,
+        op_code: u16,
+        args: String,
+    },
+}
+
+/// What could be learned about a received file descriptor.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FdInfo {
+    /// `file`, `socket`, `pipe` or `other`.
+    pub file_type: String,
+    pub size: u64,
+}
+
+impl FdInfo {
+    fn of(fd: RawFd) -> Self {
+        let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+        if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+            return FdInfo {
+                file_type: "other".to_string(),
+                size: 0,
+            };
+        }
+        let file_type = match stat.st_mode & libc::S_IFMT {
+            libc::S_IFREG => "file",
+            libc::S_IFSOCK => "socket",
+            libc::S_IFIFO => "pipe",
+            _ => "other",
+        };
+        FdInfo {
+            file_type: file_type.to_string(),
+            size: stat.st_size as u64,
+        }
+    }
+}
+
+/// Writes one client's capture file.
+///
+/// Writes are buffered but synchronous; captures are a debugging aid, not
+/// something to leave on in normal use.
+pub struct Capture {
+    writer: BufWriter<File>,
+    start: Instant,
+}
+
+impl Capture {
+    /// Creates `client-<pid>-<client_id>.jsonl` in `dir`, where `pid` is the
+    /// compositor's, so captures from earlier runs are kept. A name taken
+    /// anyway, say by a reused pid, gets a numbered suffix.
+    pub fn create(dir: &Path, client_id: u64) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let name = format!("client-{}-{}", std::process::id(), client_id);
+        let mut suffix = 0;
+        let file = loop {
+            let path = match suffix {
+                0 => dir.join(format!("{}.jsonl", name)),
+                _ => dir.join(format!("{}.{}.jsonl", name, suffix)),
+            };
+            match File::create_new(&path) {
+                Ok(file) => break file,
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => suffix += 1,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        Ok(Capture {
+            writer: BufWriter::new(file),
+            start: Instant::now(),
+        })
+    }
+
+    fn time_us(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+
+    fn write(&mut self, record: &CaptureRecord) {
+        let result = serde_json::to_writer(&mut self.writer, record)
+            .map_err(std::io::Error::from)
+            .and_then(|_| self.writer.write_all(b"\n"));
+        if let Err(e) = result {
+            warn!("Failed to write protocol capture: {}", e);
+        }
+    }
+
+    pub fn record_request(&mut self, interface: &str, message: &wire::Message) {
+        let record = CaptureRecord::Request {
+            time_us: self.time_us(),
+            interface: interface.to_string(),
+            object_id: message.object_id,
+            op_code: message.op_code,
+            args: to_hex(&message.args),
+        };
+        self.write(&record);
+    }
+
+    pub fn record_fds(&mut self, fds: &[RawFd]) {
+        let record = CaptureRecord::Fds {
+            time_us: self.time_us(),
+            fds: fds.iter().map(|&fd| FdInfo::of(fd)).collect(),
+        };
+        self.write(&record);
+    }
+
+    /// Records every event in a batch about to be written to the client.
+    pub fn record_events(&mut self, outgoing: &[u8]) {
+        let time_us = self.time_us();
+        let mut events = BytesMut::from(outgoing);
+        while let Ok(Some(event)) = wire::decode_message(&mut events) {
+            self.write(&CaptureRecord::Event {
+                time_us,
+                object_id: event.object_id,
+                op_code: event.op_code,
+                args: to_hex(&event.args),
+            });
+        }
+        if let Err(e) = self.writer.flush() {
+            warn!("Failed to write protocol capture: {}", e);
+        }
+    }
+}
+
+/// The outcome of replaying a capture.
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    pub requests: usize,
+    pub captured_events: usize,
+    pub replayed_events: usize,
+    /// Replayed events that differ from the captured event at the same
+    /// position. Timestamps and serials make some difference expected.
+    pub mismatched_events: usize,
+    /// Why dispatch stopped early, if it did.
+    pub error: Option<String>,
+}
+
+/// Feeds the requests of a capture through a fresh client's dispatch and
+/// compares the events it produces with the captured ones.
+pub async fn replay(
+    path: &Path,
+    global_state: Arc<RwLock<CompositorGlobalState>>,
+) -> anyhow::Result<ReplaySummary> {
+    let mut summary = ReplaySummary::default();
+    let mut captured_events = Vec::new();
+
+    let (mut server, mut peer) = UnixStream::pair()?;
+    // Drain events as they are written so a long capture can't fill the
+    // socket buffer
+    let reader = tokio::spawn(async move {
+        let mut events = Vec::new();
+        peer.read_to_end(&mut events).await.map(|_| events)
+    });
+
+    let mut client_state = CompositorClientState::new(&mut server, global_state);
+    let mut fds = VecDeque::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line)? {
+            CaptureRecord::Request {
+                object_id,
+                op_code,
+                args,
+                ..
+            } => {
+                if summary.error.is_some() {
+                    continue;
+                }
+                summary.requests += 1;
+                let args = from_hex(&args)?;
+                if let Err(e) = client_state
+                    .handle_message(object_id, op_code, &args, &mut fds)
+                    .await
+                {
+                    debug!("Replay stopped at request {}: {}", summary.requests, e);
+                    summary.error = Some(e.to_string());
+                }
+            }
+            CaptureRecord::Fds { fds: infos, .. } => {
+                for info in infos {
+                    fds.push_back(stand_in_fd(&info)?);
+                }
+            }
+            CaptureRecord::Event {
+                object_id,
+                op_code,
+                args,
+                ..
+            } => captured_events.push((object_id, op_code, from_hex(&args)?)),
+        }
+    }
+    client_state.flush().await?;
+    drop(client_state);
+    drop(server);
+    for fd in fds {
+        unsafe { libc::close(fd) };
+    }
+
+    let mut replayed = BytesMut::from(&reader.await??[..]);
+    let mut replayed_events = Vec::new();
+    while let Some(event) = wire::decode_message(&mut replayed)? {
+        replayed_events.push((event.object_id, event.op_code, event.args.to_vec()));
+    }
+    summary.captured_events = captured_events.len();
+    summary.replayed_events = replayed_events.len();
+    summary.mismatched_events = captured_events
+        .iter()
+        .zip(&replayed_events)
+        .filter(|(captured, replayed)| captured != replayed)
+        .count()
+        + captured_events.len().abs_diff(replayed_events.len());
+    Ok(summary)
+}
+
+/// A temporary file the size of the captured file, or an empty one for other
+/// fd types.
+fn stand_in_fd(info: &FdInfo) -> anyhow::Result<RawFd> {
+    let file = tempfile::tempfile()?;
+    if info.file_type == "file" {
+        file.set_len(info.size)?;
+    }
+    debug!("Replaying {} fd as fd {}", info.file_type, file.as_raw_fd());
+    Ok(file.into_raw_fd())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        anyhow::bail!("Odd-length hex string in capture");
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| anyhow::anyhow!("Invalid hex in capture: {:?}", pair))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn captures_never_overwrite_each_other() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = Capture::create(dir.path(), 1).unwrap();
+        let second = Capture::create(dir.path(), 1).unwrap();
+        drop((first, second));
+        let mut names = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        let pid = std::process::id();
+        assert_eq!(
+            names,
+            [
+                format!("client-{}-1.1.jsonl", pid),
+                format!("client-{}-1.jsonl", pid)
+            ]
+        );
+    }
+}
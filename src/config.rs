@@ -0,0 +1,303 @@
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+use tracing::debug;
+
+/// Compositor configuration, read from `config.toml`.
+///
+/// Every section falls back to its defaults, so a missing file or section is
+/// equivalent to an empty one.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub limits: LimitsConfig,
+    pub metrics: MetricsConfig,
+    pub runtime: RuntimeConfig,
+    pub session: SessionConfig,
+    pub capture: CaptureConfig,
+    pub policy: PolicyConfig,
+    pub keyboard: KeyboardConfig,
+    pub ping: PingConfig,
+    /// Commands to run at startup. Config is only read at startup, so these
+    /// currently behave the same as `exec-once`; only `exec` entries are
+    /// meant to rerun once config reload exists.
+    pub exec: Vec<ExecConfig>,
+    /// Commands to run once, when the compositor starts.
+    #[serde(rename = "exec-once")]
+    pub exec_once: Vec<ExecConfig>,
+}
+
+/// Per-client request throttling.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Sustained requests per second a client may send.
+    pub requests_per_second: u32,
+    /// Requests a client may send in a burst above the sustained rate.
+    pub burst: u32,
+    /// How long reading from a client pauses once its burst is used up.
+    pub pause_ms: u64,
+    /// Misbehavior score at which a client is disconnected.
+    pub max_misbehavior_score: u32,
+    /// Total bytes of shm pools a client may have mapped.
+    pub max_shm_bytes: usize,
+    /// Buffers a client may have alive at once.
+    pub max_buffers: usize,
+    /// Protocol objects a client may have alive at once.
+    pub max_objects: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            requests_per_second: 50_000,
+            burst: 100_000,
+            pause_ms: 10,
+            max_misbehavior_score: 100,
+            max_shm_bytes: 1 << 30,
+            max_buffers: 4096,
+            max_objects: 65536,
+        }
+    }
+}
+
+/// The optional OpenMetrics endpoint.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// TCP address to serve `/metrics` on, e.g. `127.0.0.1:9464`; disabled if unset.
+    pub listen: Option<String>,
+}
+
+/// The async runtime the compositor runs on.
+///
+/// All shared state is `Send + Sync` and every client is its own task, so
+/// both flavors run the same code; the current-thread runtime trades
+/// throughput across many clients for lower scheduling latency.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeConfig {
+    pub flavor: RuntimeFlavor,
+    /// Worker threads for the multi-threaded runtime; one per core if unset.
+    pub worker_threads: Option<usize>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuntimeFlavor {
+    CurrentThread,
+    #[default]
+    MultiThread,
+}
+
+/// How the compositor announces itself to the user session.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// Export the client environment to systemd and D-Bus on startup. Off by
+    /// default, since it redirects every app the session later starts to this
+    /// compositor, which is wrong when running nested or for testing.
+    pub export_environment: bool,
+    /// `XDG_CURRENT_DESKTOP`, which portals use to pick their backends.
+    pub desktop_name: String,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            export_environment: false,
+            desktop_name: "way-too-far".to_string(),
+        }
+    }
+}
+
+/// Protocol capture, for debugging client-specific protocol bugs.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// Directory to write a capture file per client to; disabled if unset.
+    /// Replay one with `--replay <file>`.
+    pub dir: Option<PathBuf>,
+}
+
+/// The keyboard layout, as xkb RMLVO names, and key repeat.
+///
+/// Empty names use libxkbcommon's defaults, which honor the `XKB_DEFAULT_*`
+/// environment variables.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyboardConfig {
+    pub rules: String,
+    pub model: String,
+    /// Comma-separated layouts, e.g. `us,de`.
+    pub layout: String,
+    pub variant: String,
+    /// e.g. `caps:escape`.
+    pub options: String,
+    /// Repeats per second of a held key; 0 disables repeat.
+    pub repeat_rate: u32,
+    /// Milliseconds a key is held before it starts repeating.
+    pub repeat_delay: u32,
+}
+
+impl Default for KeyboardConfig {
+    fn default() -> Self {
+        KeyboardConfig {
+            rules: String::new(),
+            model: String::new(),
+            layout: String::new(),
+            variant: String::new(),
+            options: String::new(),
+            repeat_rate: 25,
+            repeat_delay: 600,
+        }
+    }
+}
+
+/// Liveness checks of clients with an xdg_wm_base, which are pinged when
+/// they gain focus or are sent a key or button press.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PingConfig {
+    /// How long a client has to answer before it is reported unresponsive.
+    pub timeout_ms: u64,
+}
+
+impl Default for PingConfig {
+    fn default() -> Self {
+        PingConfig { timeout_ms: 5000 }
+    }
+}
+
+/// Which clients each global is advertised to.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// Globals restricted to an allow-list, keyed by interface name, e.g.
+    /// `[policy.globals.xdg_wm_base]`. Globals not listed are advertised to
+    /// every client.
+    pub globals: HashMap<String, GlobalPolicy>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GlobalPolicy {
+    /// Executable paths of the clients allowed to see and bind the global.
+    pub allow: Vec<PathBuf>,
+}
+
+impl PolicyConfig {
+    /// Whether a client running `executable` may use `interface`. Clients
+    /// whose executable is unknown only get unrestricted globals.
+    pub fn allows(&self, interface: &str, executable: Option<&Path>) -> bool {
+        match self.globals.get(interface) {
+            Some(policy) => executable
+                .is_some_and(|executable| policy.allow.iter().any(|allowed| allowed == executable)),
+            None => true,
+        }
+    }
+}
+
+/// A command spawned by the compositor, written either as a plain string or
+/// as a table with options.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "ExecEntry")]
+pub struct ExecConfig {
+    /// Run through `sh -c`.
+    pub command: String,
+    /// Restart the command whenever it exits, for critical clients like a
+    /// bar or wallpaper.
+    pub restart: bool,
+    /// Hand the command an already-connected socket through `WAYLAND_SOCKET`
+    /// instead of setting `WAYLAND_DISPLAY`.
+    pub wayland_socket: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ExecEntry {
+    Command(String),
+    Table(ExecTable),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExecTable {
+    command: String,
+    #[serde(default)]
+    restart: bool,
+    #[serde(default)]
+    wayland_socket: bool,
+}
+
+impl From<ExecEntry> for ExecConfig {
+    fn from(entry: ExecEntry) -> Self {
+        match entry {
+            ExecEntry::Command(command) => ExecConfig {
+                command,
+                restart: false,
+                wayland_socket: false,
+            },
+            ExecEntry::Table(table) => ExecConfig {
+                command: table.command,
+                restart: table.restart,
+                wayland_socket: table.wayland_socket,
+            },
+        }
+    }
+}
+
+impl Config {
+    /// Loads `$WAY_TOO_FAR_CONFIG`, or `way-too-far/config.toml` under the XDG
+    /// config directory, falling back to defaults if neither exists.
+    pub fn load() -> anyhow::Result<Self> {
+        let Some(path) = Self::path() else {
+            return Ok(Config::default());
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                debug!("Loading config from {}", path.display());
+                toml::from_str(&contents)
+                    .map_err(|e| anyhow::anyhow!("Invalid config {}: {}", path.display(), e))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(anyhow::anyhow!(
+                "Failed to read config {}: {}",
+                path.display(),
+                e
+            )),
+        }
+    }
+
+    fn path() -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os("WAY_TOO_FAR_CONFIG") {
+            return Some(PathBuf::from(path));
+        }
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(config_dir.join("way-too-far").join("config.toml"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_environment_is_not_exported_by_default() {
+        assert!(!Config::default().session.export_environment);
+        let config: Config = toml::from_str("").unwrap();
+        assert!(!config.session.export_environment);
+        let config: Config = toml::from_str("[session]\ndesktop_name = \"test\"\n").unwrap();
+        assert!(!config.session.export_environment);
+    }
+
+    #[test]
+    fn session_environment_is_exported_when_asked() {
+        let config: Config = toml::from_str("[session]\nexport_environment = true\n").unwrap();
+        assert!(config.session.export_environment);
+    }
+}
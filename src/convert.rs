@@ -0,0 +1,268 @@
+//! Pixel format conversions used when uploading client buffers.
+//!
+//! Every conversion has a scalar implementation plus vector paths picked at
+//! runtime: SSE2 (always present on x86_64) upgraded to SSSE3 or AVX2 when
+//! the CPU has them, and NEON on aarch64. The vector paths handle whole
+//! blocks of pixels and leave the remainder to the scalar code, so all paths
+//! produce bit-identical output.
+//!
+//! Source rows are little-endian byte slices straight from the shm pool;
+//! destinations are ARGB8888 pixels as `u32`. Each function converts as many
+//! pixels as fit in both slices.
+
+/// Converts ARGB8888 pixels without modification; wl_shm alpha is already
+/// premultiplied, which is the form the texture keeps.
+pub fn argb8888_to_argb(src: &[u8], dst: &mut [u32]) {
+    // A plain little-endian copy; the compiler vectorises this on its own
+    for (pixel, bytes) in dst.iter_mut().zip(src.chunks_exact(4)) {
+        *pixel = u32::from_le_bytes(bytes.try_into().unwrap());
+    }
+}
+
+/// Converts XRGB8888 pixels, forcing the unused byte to opaque alpha.
+pub fn xrgb8888_to_argb(src: &[u8], dst: &mut [u32]) {
+    let count = dst.len().min(src.len() / 4);
+    let (src, dst) = (&src[..count * 4], &mut dst[..count]);
+    let done = simd::xrgb8888_to_argb(src, dst);
+    scalar::xrgb8888_to_argb(&src[done * 4..], &mut dst[done..]);
+}
+
+/// Expands RGB888 pixels (stored as B, G, R bytes) to opaque ARGB8888.
+pub fn rgb888_to_argb(src: &[u8], dst: &mut [u32]) {
+    let count = dst.len().min(src.len() / 3);
+    let (src, dst) = (&src[..count * 3], &mut dst[..count]);
+    let done = simd::rgb888_to_argb(src, dst);
+    scalar::rgb888_to_argb(&src[done * 3..], &mut dst[done..]);
+}
+
+mod scalar {
+    pub fn xrgb8888_to_argb(src: &[u8], dst: &mut [u32]) {
+        for (pixel, bytes) in dst.iter_mut().zip(src.chunks_exact(4)) {
+            *pixel = 0xff00_0000 | u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+    }
+
+    pub fn rgb888_to_argb(src: &[u8], dst: &mut [u32]) {
+        for (pixel, bytes) in dst.iter_mut().zip(src.chunks_exact(3)) {
+            *pixel =
+                0xff00_0000 | (bytes[2] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[0] as u32;
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod simd {
+    //! Each function returns how many leading pixels it converted.
+
+    use std::arch::x86_64::*;
+
+    pub fn xrgb8888_to_argb(src: &[u8], dst: &mut [u32]) -> usize {
+        if is_x86_feature_detected!("avx2") {
+            unsafe { xrgb8888_to_argb_avx2(src, dst) }
+        } else {
+            xrgb8888_to_argb_sse2(src, dst)
+        }
+    }
+
+    pub fn rgb888_to_argb(src: &[u8], dst: &mut [u32]) -> usize {
+        if is_x86_feature_detected!("avx2") {
+            unsafe { rgb888_to_argb_avx2(src, dst) }
+        } else if is_x86_feature_detected!("ssse3") {
+            unsafe { rgb888_to_argb_ssse3(src, dst) }
+        } else {
+            0
+        }
+    }
+
+    pub fn xrgb8888_to_argb_sse2(src: &[u8], dst: &mut [u32]) -> usize {
+        let blocks = dst.len().min(src.len() / 4) / 4 * 4;
+        unsafe {
+            let alpha = _mm_set1_epi32(0xff00_0000_u32 as i32);
+            for i in (0..blocks).step_by(4) {
+                let pixels = _mm_loadu_si128(src.as_ptr().add(i * 4) as *const __m128i);
+                _mm_storeu_si128(
+                    dst.as_mut_ptr().add(i) as *mut __m128i,
+                    _mm_or_si128(pixels, alpha),
+                );
+            }
+        }
+        blocks
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn xrgb8888_to_argb_avx2(src: &[u8], dst: &mut [u32]) -> usize {
+        let blocks = dst.len().min(src.len() / 4) / 8 * 8;
+        unsafe {
+            let alpha = _mm256_set1_epi32(0xff00_0000_u32 as i32);
+            for i in (0..blocks).step_by(8) {
+                let pixels = _mm256_loadu_si256(src.as_ptr().add(i * 4) as *const __m256i);
+                _mm256_storeu_si256(
+                    dst.as_mut_ptr().add(i) as *mut __m256i,
+                    _mm256_or_si256(pixels, alpha),
+                );
+            }
+        }
+        blocks
+    }
+
+    /// Shuffle spreading four packed 3-byte pixels over four 32-bit lanes.
+    const RGB888_SHUFFLE: [i8; 16] = [0, 1, 2, -1, 3, 4, 5, -1, 6, 7, 8, -1, 9, 10, 11, -1];
+
+    #[target_feature(enable = "ssse3")]
+    pub unsafe fn rgb888_to_argb_ssse3(src: &[u8], dst: &mut [u32]) -> usize {
+        // Each step reads 16 bytes but consumes 12, so stop while a full
+        // load still fits in the source
+        let mut i = 0;
+        unsafe {
+            let shuffle = _mm_loadu_si128(RGB888_SHUFFLE.as_ptr() as *const __m128i);
+            let alpha = _mm_set1_epi32(0xff00_0000_u32 as i32);
+            while i + 4 <= dst.len() && i * 3 + 16 <= src.len() {
+                let bytes = _mm_loadu_si128(src.as_ptr().add(i * 3) as *const __m128i);
+                let pixels = _mm_or_si128(_mm_shuffle_epi8(bytes, shuffle), alpha);
+                _mm_storeu_si128(dst.as_mut_ptr().add(i) as *mut __m128i, pixels);
+                i += 4;
+            }
+        }
+        i
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn rgb888_to_argb_avx2(src: &[u8], dst: &mut [u32]) -> usize {
+        // The shuffle works within 128-bit lanes, so each lane is loaded
+        // separately with the second starting four pixels in
+        let mut i = 0;
+        unsafe {
+            let lane_shuffle = _mm_loadu_si128(RGB888_SHUFFLE.as_ptr() as *const __m128i);
+            let shuffle = _mm256_set_m128i(lane_shuffle, lane_shuffle);
+            let alpha = _mm256_set1_epi32(0xff00_0000_u32 as i32);
+            while i + 8 <= dst.len() && i * 3 + 28 <= src.len() {
+                let low = _mm_loadu_si128(src.as_ptr().add(i * 3) as *const __m128i);
+                let high = _mm_loadu_si128(src.as_ptr().add(i * 3 + 12) as *const __m128i);
+                let bytes = _mm256_set_m128i(high, low);
+                let pixels = _mm256_or_si256(_mm256_shuffle_epi8(bytes, shuffle), alpha);
+                _mm256_storeu_si256(dst.as_mut_ptr().add(i) as *mut __m256i, pixels);
+                i += 8;
+            }
+        }
+        i
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod simd {
+    //! Each function returns how many leading pixels it converted.
+
+    use std::arch::aarch64::*;
+
+    pub fn xrgb8888_to_argb(src: &[u8], dst: &mut [u32]) -> usize {
+        let blocks = dst.len().min(src.len() / 4) / 4 * 4;
+        unsafe {
+            let alpha = vdupq_n_u32(0xff00_0000);
+            for i in (0..blocks).step_by(4) {
+                let pixels = vld1q_u32(src.as_ptr().add(i * 4) as *const u32);
+                vst1q_u32(dst.as_mut_ptr().add(i), vorrq_u32(pixels, alpha));
+            }
+        }
+        blocks
+    }
+
+    pub fn rgb888_to_argb(src: &[u8], dst: &mut [u32]) -> usize {
+        let blocks = dst.len().min(src.len() / 3) / 8 * 8;
+        unsafe {
+            let alpha = vdup_n_u8(0xff);
+            for i in (0..blocks).step_by(8) {
+                let bgr = vld3_u8(src.as_ptr().add(i * 3));
+                let bgra = uint8x8x4_t(bgr.0, bgr.1, bgr.2, alpha);
+                vst4_u8(dst.as_mut_ptr().add(i) as *mut u8, bgra);
+            }
+        }
+        blocks
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod simd {
+    pub fn xrgb8888_to_argb(_src: &[u8], _dst: &mut [u32]) -> usize {
+        0
+    }
+
+    pub fn rgb888_to_argb(_src: &[u8], _dst: &mut [u32]) -> usize {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic pseudo-random bytes, so failures reproduce.
+    fn test_bytes(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    // Lengths cover empty rows, rows shorter than one vector block and
+    // every remainder after the widest block
+    const LENGTHS: [usize; 12] = [0, 1, 2, 3, 4, 5, 7, 8, 9, 15, 17, 1921];
+
+    #[test]
+    fn xrgb8888_matches_scalar() {
+        for len in LENGTHS {
+            let src = test_bytes(len * 4, len as u32);
+            let mut expected = vec![0; len];
+            let mut actual = vec![0; len];
+            scalar::xrgb8888_to_argb(&src, &mut expected);
+            xrgb8888_to_argb(&src, &mut actual);
+            assert_eq!(expected, actual, "{} pixels", len);
+        }
+    }
+
+    #[test]
+    fn rgb888_matches_scalar() {
+        for len in LENGTHS {
+            let src = test_bytes(len * 3, len as u32);
+            let mut expected = vec![0; len];
+            let mut actual = vec![0; len];
+            scalar::rgb888_to_argb(&src, &mut expected);
+            rgb888_to_argb(&src, &mut actual);
+            assert_eq!(expected, actual, "{} pixels", len);
+        }
+    }
+
+    #[test]
+    fn rgb888_byte_order() {
+        let mut dst = [0];
+        rgb888_to_argb(&[0x11, 0x22, 0x33], &mut dst);
+        assert_eq!(dst, [0xff33_2211]);
+    }
+
+    /// The dispatchers pick the widest path, so check the narrower ones directly.
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn x86_fallback_paths_match_scalar() {
+        for len in LENGTHS {
+            let src = test_bytes(len * 4, len as u32);
+            let mut expected = vec![0; len];
+            let mut actual = vec![0; len];
+            scalar::xrgb8888_to_argb(&src, &mut expected);
+            let done = simd::xrgb8888_to_argb_sse2(&src, &mut actual);
+            scalar::xrgb8888_to_argb(&src[done * 4..], &mut actual[done..]);
+            assert_eq!(expected, actual, "sse2 xrgb8888, {} pixels", len);
+
+            if is_x86_feature_detected!("ssse3") {
+                let src = test_bytes(len * 3, len as u32);
+                let mut expected = vec![0; len];
+                let mut actual = vec![0; len];
+                scalar::rgb888_to_argb(&src, &mut expected);
+                let done = unsafe { simd::rgb888_to_argb_ssse3(&src, &mut actual) };
+                scalar::rgb888_to_argb(&src[done * 3..], &mut actual[done..]);
+                assert_eq!(expected, actual, "ssse3 rgb888, {} pixels", len);
+            }
+        }
+    }
+}
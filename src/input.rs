@@ -0,0 +1,672 @@
+use crate::{
+    CompositorGlobalState,
+    config::KeyboardConfig,
+    wl_keyboard::KeymapFile,
+    wl_seat::WlSeatCapability,
+    xkb::{KeyState, Keymap, Modifiers},
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc::error::TrySendError, watch};
+use tracing::{debug, warn};
+
+/// Seat events a client may have queued before it falls behind and starts
+/// missing them.
+pub const SEAT_EVENT_BUFFER: usize = 1024;
+
+/// Input routed to one client, which turns it into events on its own
+/// device objects.
+#[derive(Debug, Clone)]
+pub enum SeatEvent {
+    /// The seat's capabilities changed.
+    Capabilities(u32),
+    Pointer(PointerEvent),
+    Keyboard(KeyboardEvent),
+    Touch(TouchEvent),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PointerEvent {
+    Enter {
+        surface_id: u32,
+        x: f64,
+        y: f64,
+    },
+    Leave {
+        surface_id: u32,
+    },
+    Motion {
+        time: u32,
+        x: f64,
+        y: f64,
+    },
+    Button {
+        time: u32,
+        button: u32,
+        pressed: bool,
+    },
+    Axis {
+        time: u32,
+        axis: PointerAxis,
+        value: f64,
+    },
+    Frame,
+}
+
+#[derive(Debug, Clone)]
+pub enum KeyboardEvent {
+    /// `keys` are the evdev codes held as focus arrives.
+    Enter {
+        surface_id: u32,
+        keys: Vec<u32>,
+        modifiers: Modifiers,
+    },
+    Leave {
+        surface_id: u32,
+    },
+    Key {
+        time: u32,
+        key: u32,
+        pressed: bool,
+    },
+    /// A held key repeating, sent as another press to keyboards that leave
+    /// repeat to the compositor.
+    Repeat {
+        time: u32,
+        key: u32,
+    },
+    Modifiers(Modifiers),
+}
+
+/// `id` identifies a touch point from down to up, and is only reused after
+/// it went up.
+#[derive(Debug, Clone, Copy)]
+pub enum TouchEvent {
+    Down {
+        time: u32,
+        surface_id: u32,
+        id: i32,
+        x: f64,
+        y: f64,
+    },
+    Up {
+        time: u32,
+        id: i32,
+    },
+    Motion {
+        time: u32,
+        id: i32,
+        x: f64,
+        y: f64,
+    },
+    Frame,
+    /// Every touch point of the client is gone, without up events.
+    Cancel,
+}
+
+impl From<PointerEvent> for SeatEvent {
+    fn from(event: PointerEvent) -> Self {
+        SeatEvent::Pointer(event)
+    }
+}
+
+impl From<KeyboardEvent> for SeatEvent {
+    fn from(event: KeyboardEvent) -> Self {
+        SeatEvent::Keyboard(event)
+    }
+}
+
+impl From<TouchEvent> for SeatEvent {
+    fn from(event: TouchEvent) -> Self {
+        SeatEvent::Touch(event)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum PointerAxis {
+    VerticalScroll = 0,
+    HorizontalScroll = 1,
+}
+
+/// Milliseconds on the clock input event timestamps are taken from.
+pub fn event_time() -> u32 {
+    static START: LazyLock<Instant> = LazyLock::new(Instant::now);
+    START.elapsed().as_millis() as u32
+}
+
+/// A mapped surface that can receive input.
+struct SceneSurface {
+    client_id: u64,
+    surface_id: u32,
+    width: i32,
+    height: i32,
+}
+
+/// The surfaces input is picked from, bottom to top.
+///
+/// There is no window placement yet, so every surface sits at the origin and
+/// the most recently mapped one is on top.
+#[derive(Default)]
+pub struct Scene {
+    surfaces: Vec<SceneSurface>,
+}
+
+impl Scene {
+    /// Maps a surface on top, or updates its size if already mapped.
+    pub fn map(&mut self, client_id: u64, surface_id: u32, width: i32, height: i32) {
+        match self
+            .surfaces
+            .iter_mut()
+            .find(|surface| surface.client_id == client_id && surface.surface_id == surface_id)
+        {
+            Some(surface) => {
+                surface.width = width;
+                surface.height = height;
+            }
+            None => self.surfaces.push(SceneSurface {
+                client_id,
+                surface_id,
+                width,
+                height,
+            }),
+        }
+    }
+
+    pub fn unmap(&mut self, client_id: u64, surface_id: u32) {
+        self.surfaces.retain(|surface| {
+            !(surface.client_id == client_id && surface.surface_id == surface_id)
+        });
+    }
+
+    pub fn remove_client(&mut self, client_id: u64) {
+        self.surfaces
+            .retain(|surface| surface.client_id != client_id);
+    }
+
+    pub fn contains(&self, client_id: u64, surface_id: u32) -> bool {
+        self.surfaces
+            .iter()
+            .any(|surface| surface.client_id == client_id && surface.surface_id == surface_id)
+    }
+
+    /// The most recently mapped surface, as `(client_id, surface_id)`.
+    pub fn top(&self) -> Option<(u64, u32)> {
+        self.surfaces
+            .last()
+            .map(|surface| (surface.client_id, surface.surface_id))
+    }
+
+    /// The topmost surface containing `(x, y)`, as `(client_id, surface_id)`.
+    pub fn surface_at(&self, x: f64, y: f64) -> Option<(u64, u32)> {
+        self.surfaces
+            .iter()
+            .rev()
+            .find(|surface| {
+                x >= 0.0 && y >= 0.0 && x < surface.width as f64 && y < surface.height as f64
+            })
+            .map(|surface| (surface.client_id, surface.surface_id))
+    }
+}
+
+/// The seat's pointer.
+#[derive(Default)]
+pub struct Pointer {
+    pub x: f64,
+    pub y: f64,
+    /// `(client_id, surface_id)` of the surface with pointer focus.
+    focus: Option<(u64, u32)>,
+    /// Buttons held down; focus stays put until all are released, so a drag
+    /// keeps going to the surface it started on.
+    buttons_down: usize,
+}
+
+/// The seat's touchscreen.
+#[derive(Default)]
+pub struct Touch {
+    /// Whether a touch device is attached, which the seat advertises as
+    /// the touch capability.
+    present: bool,
+    /// `(client_id, surface_id)` each touch point went down on, by id. A
+    /// point stays with its surface until it goes up.
+    points: HashMap<i32, (u64, u32)>,
+}
+
+/// The seat's keyboard.
+pub struct Keyboard {
+    /// Sealed, so every client can be sent the same fd.
+    pub keymap: Arc<KeymapFile>,
+    state: KeyState,
+    /// Evdev codes of held keys.
+    pressed: Vec<u32>,
+    /// `(client_id, surface_id)` of the surface with keyboard focus.
+    focus: Option<(u64, u32)>,
+    /// Repeats per second, or 0 for no repeat.
+    pub repeat_rate: u32,
+    /// Milliseconds before a held key starts repeating.
+    pub repeat_delay: u32,
+    /// The key [`repeat_keys`] is repeating, which is the last repeating
+    /// key pressed while it stays held and focus stays put.
+    repeating: watch::Sender<Option<u32>>,
+}
+
+impl Keyboard {
+    pub fn new(config: &KeyboardConfig) -> anyhow::Result<Keyboard> {
+        let keymap = Keymap::new(config)?;
+        Ok(Keyboard {
+            keymap: Arc::new(KeymapFile::new(&keymap.as_string()?)?),
+            state: KeyState::new(&keymap)?,
+            pressed: Vec::new(),
+            focus: None,
+            repeat_rate: config.repeat_rate,
+            repeat_delay: config.repeat_delay,
+            repeating: watch::Sender::new(None),
+        })
+    }
+}
+
+/// Repeats held keys on the seat's keyboard until the runtime shuts down.
+///
+/// Keyboards from version 4 are told to leave repeat to the compositor, so
+/// they get repeats from here; older ones repeat on their own.
+pub async fn repeat_keys(global_state: Arc<RwLock<CompositorGlobalState>>) {
+    let keyboard = global_state.read().ok().and_then(|global_state| {
+        let keyboard = global_state.keyboard.as_ref()?;
+        (keyboard.repeat_rate > 0).then(|| {
+            (
+                keyboard.repeating.subscribe(),
+                Duration::from_millis(keyboard.repeat_delay.into()),
+                Duration::from_secs(1) / keyboard.repeat_rate,
+            )
+        })
+    });
+    let Some((mut repeating, delay, interval)) = keyboard else {
+        return;
+    };
+    loop {
+        let Some(key) = *repeating.borrow_and_update() else {
+            if repeating.changed().await.is_err() {
+                return;
+            }
+            continue;
+        };
+        let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + delay, interval);
+        loop {
+            tokio::select! {
+                changed = repeating.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    break;
+                }
+                _ = ticks.tick() => {
+                    let Ok(mut global_state) = global_state.write() else {
+                        return;
+                    };
+                    global_state.keyboard_repeat(key);
+                }
+            }
+        }
+    }
+}
+
+impl CompositorGlobalState {
+    /// Queues a seat event for a client, dropping it if the client is gone
+    /// or too far behind.
+    fn send_seat_event(&self, client_id: u64, event: impl Into<SeatEvent>) {
+        let Some(sender) = self.seat_events.get(&client_id) else {
+            return;
+        };
+        match sender.try_send(event.into()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(event)) => {
+                warn!(
+                    "Client {} is not keeping up, dropped {:?}",
+                    client_id, event
+                )
+            }
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+
+    /// Updates pointer and keyboard focus after the scene changed.
+    pub fn update_focus(&mut self) {
+        self.update_pointer_focus();
+        self.update_keyboard_focus();
+        self.update_touch_points();
+    }
+
+    /// Moves pointer focus to the surface under the pointer, sending leave
+    /// and enter as needed. Returns whether focus changed.
+    pub fn update_pointer_focus(&mut self) -> bool {
+        if self.pointer.buttons_down > 0 {
+            if let Some((client_id, surface_id)) = self.pointer.focus
+                && self.scene.contains(client_id, surface_id)
+            {
+                return false;
+            }
+            // The grabbed surface went away
+            self.pointer.buttons_down = 0;
+        }
+        let under = self.scene.surface_at(self.pointer.x, self.pointer.y);
+        if under == self.pointer.focus {
+            return false;
+        }
+        if let Some((client_id, surface_id)) = self.pointer.focus {
+            self.send_seat_event(client_id, PointerEvent::Leave { surface_id });
+            self.send_seat_event(client_id, PointerEvent::Frame);
+        }
+        if let Some((client_id, surface_id)) = under {
+            debug!(
+                "Pointer focus moved to surface {} of client {}",
+                surface_id, client_id
+            );
+            self.send_seat_event(
+                client_id,
+                PointerEvent::Enter {
+                    surface_id,
+                    x: self.pointer.x,
+                    y: self.pointer.y,
+                },
+            );
+            self.send_seat_event(client_id, PointerEvent::Frame);
+        }
+        self.pointer.focus = under;
+        true
+    }
+
+    /// Moves the pointer to `(x, y)` in global coordinates.
+    pub fn pointer_motion(&mut self, x: f64, y: f64) {
+        self.pointer.x = x;
+        self.pointer.y = y;
+        if self.update_pointer_focus() {
+            return;
+        }
+        if let Some((client_id, _)) = self.pointer.focus {
+            // Surfaces sit at the origin, so surface-local and global
+            // coordinates are the same
+            let time = event_time();
+            self.send_seat_event(client_id, PointerEvent::Motion { time, x, y });
+            self.send_seat_event(client_id, PointerEvent::Frame);
+        }
+    }
+
+    /// Presses or releases a button, using Linux input event codes.
+    pub fn pointer_button(&mut self, button: u32, pressed: bool) {
+        let Some((client_id, _)) = self.pointer.focus else {
+            return;
+        };
+        if pressed {
+            // Click to focus
+            self.set_keyboard_focus(self.pointer.focus);
+            self.pointer.buttons_down += 1;
+        } else {
+            self.pointer.buttons_down = self.pointer.buttons_down.saturating_sub(1);
+        }
+        let time = event_time();
+        self.send_seat_event(
+            client_id,
+            PointerEvent::Button {
+                time,
+                button,
+                pressed,
+            },
+        );
+        self.send_seat_event(client_id, PointerEvent::Frame);
+        if !pressed {
+            self.update_pointer_focus();
+        }
+    }
+
+    /// Scrolls by `vertical` and `horizontal` surface-local pixels.
+    pub fn pointer_axis(&mut self, vertical: f64, horizontal: f64) {
+        let Some((client_id, _)) = self.pointer.focus else {
+            return;
+        };
+        let time = event_time();
+        for (axis, value) in [
+            (PointerAxis::VerticalScroll, vertical),
+            (PointerAxis::HorizontalScroll, horizontal),
+        ] {
+            if value != 0.0 {
+                self.send_seat_event(client_id, PointerEvent::Axis { time, axis, value });
+            }
+        }
+        self.send_seat_event(client_id, PointerEvent::Frame);
+    }
+
+    /// Keeps keyboard focus on its surface while that stays mapped, and
+    /// otherwise moves it to the topmost surface.
+    pub fn update_keyboard_focus(&mut self) {
+        let Some(keyboard) = &self.keyboard else {
+            return;
+        };
+        if let Some((client_id, surface_id)) = keyboard.focus
+            && self.scene.contains(client_id, surface_id)
+        {
+            return;
+        }
+        self.set_keyboard_focus(self.scene.top());
+    }
+
+    /// Moves keyboard focus, sending leave and enter as needed.
+    pub fn set_keyboard_focus(&mut self, focus: Option<(u64, u32)>) {
+        let Some(keyboard) = &mut self.keyboard else {
+            return;
+        };
+        if keyboard.focus == focus {
+            return;
+        }
+        let old_focus = std::mem::replace(&mut keyboard.focus, focus);
+        keyboard.repeating.send_replace(None);
+        let keys = keyboard.pressed.clone();
+        let modifiers = keyboard.state.modifiers();
+        if let Some((client_id, surface_id)) = old_focus {
+            self.send_seat_event(client_id, KeyboardEvent::Leave { surface_id });
+        }
+        if let Some((client_id, surface_id)) = focus {
+            debug!(
+                "Keyboard focus moved to surface {} of client {}",
+                surface_id, client_id
+            );
+            self.send_seat_event(
+                client_id,
+                KeyboardEvent::Enter {
+                    surface_id,
+                    keys,
+                    modifiers,
+                },
+            );
+        }
+    }
+
+    /// Presses or releases a key, given as a Linux input event code.
+    pub fn keyboard_key(&mut self, key: u32, pressed: bool) {
+        let Some(keyboard) = &mut self.keyboard else {
+            return;
+        };
+        if pressed == keyboard.pressed.contains(&key) {
+            // Pressing a held key or releasing a free one changes nothing
+            return;
+        }
+        if pressed {
+            keyboard.pressed.push(key);
+            if keyboard.repeat_rate > 0 && keyboard.state.key_repeats(key) {
+                keyboard.repeating.send_replace(Some(key));
+            }
+        } else {
+            keyboard.pressed.retain(|&held| held != key);
+            if *keyboard.repeating.borrow() == Some(key) {
+                keyboard.repeating.send_replace(None);
+            }
+        }
+        let modifiers_changed = keyboard.state.update_key(key, pressed);
+        let modifiers = keyboard.state.modifiers();
+        let Some((client_id, _)) = keyboard.focus else {
+            return;
+        };
+        let time = event_time();
+        self.send_seat_event(client_id, KeyboardEvent::Key { time, key, pressed });
+        if modifiers_changed {
+            self.send_seat_event(client_id, KeyboardEvent::Modifiers(modifiers));
+        }
+    }
+
+    /// Sends a repeat of `key` if it is still the one repeating.
+    fn keyboard_repeat(&mut self, key: u32) {
+        let Some(keyboard) = &self.keyboard else {
+            return;
+        };
+        if *keyboard.repeating.borrow() != Some(key) {
+            return;
+        }
+        let Some((client_id, _)) = keyboard.focus else {
+            return;
+        };
+        let time = event_time();
+        self.send_seat_event(client_id, KeyboardEvent::Repeat { time, key });
+    }
+
+    /// Attaches or detaches the touch device, cancelling any touch points
+    /// on detach.
+    pub fn set_touch_present(&mut self, present: bool) {
+        if self.touch.present == present {
+            return;
+        }
+        self.touch.present = present;
+        if present {
+            self.seat.capabilities |= WlSeatCapability::Touch as u32;
+            self.seat.capabilities_ever |= WlSeatCapability::Touch as u32;
+        } else {
+            self.seat.capabilities &= !(WlSeatCapability::Touch as u32);
+            self.touch_cancel();
+        }
+        for &client_id in self.seat_events.keys() {
+            self.send_seat_event(client_id, SeatEvent::Capabilities(self.seat.capabilities));
+        }
+    }
+
+    /// Puts touch point `id` down at `(x, y)` in global coordinates, on
+    /// whichever surface is there.
+    pub fn touch_down(&mut self, id: i32, x: f64, y: f64) {
+        if !self.touch.present || self.touch.points.contains_key(&id) {
+            return;
+        }
+        let Some((client_id, surface_id)) = self.scene.surface_at(x, y) else {
+            return;
+        };
+        self.touch.points.insert(id, (client_id, surface_id));
+        let time = event_time();
+        self.send_seat_event(
+            client_id,
+            TouchEvent::Down {
+                time,
+                surface_id,
+                id,
+                x,
+                y,
+            },
+        );
+        self.send_seat_event(client_id, TouchEvent::Frame);
+    }
+
+    /// Moves touch point `id`, which stays with the surface it went down on.
+    pub fn touch_motion(&mut self, id: i32, x: f64, y: f64) {
+        let Some(&(client_id, _)) = self.touch.points.get(&id) else {
+            return;
+        };
+        let time = event_time();
+        self.send_seat_event(client_id, TouchEvent::Motion { time, id, x, y });
+        self.send_seat_event(client_id, TouchEvent::Frame);
+    }
+
+    pub fn touch_up(&mut self, id: i32) {
+        let Some((client_id, _)) = self.touch.points.remove(&id) else {
+            return;
+        };
+        let time = event_time();
+        self.send_seat_event(client_id, TouchEvent::Up { time, id });
+        self.send_seat_event(client_id, TouchEvent::Frame);
+    }
+
+    /// Drops every touch point, e.g. when a gesture is taken over by the
+    /// compositor or the device goes away.
+    pub fn touch_cancel(&mut self) {
+        let mut clients = self
+            .touch
+            .points
+            .drain()
+            .map(|(_, (client_id, _))| client_id)
+            .collect::<Vec<_>>();
+        clients.sort_unstable();
+        clients.dedup();
+        for client_id in clients {
+            self.send_seat_event(client_id, TouchEvent::Cancel);
+        }
+    }
+
+    /// Lifts touch points whose surface was unmapped.
+    fn update_touch_points(&mut self) {
+        let lifted = self
+            .touch
+            .points
+            .iter()
+            .filter(|(_, (client_id, surface_id))| !self.scene.contains(*client_id, *surface_id))
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for id in lifted {
+            self.touch_up(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use tokio::sync::mpsc;
+
+    const KEY_A: u32 = 30;
+
+    fn repeats(events: &mut mpsc::Receiver<SeatEvent>) -> usize {
+        std::iter::from_fn(|| events.try_recv().ok())
+            .filter(|event| matches!(event, SeatEvent::Keyboard(KeyboardEvent::Repeat { .. })))
+            .count()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn held_key_repeats_at_the_configured_rate() {
+        let mut config = Config::default();
+        config.keyboard.repeat_delay = 500;
+        config.keyboard.repeat_rate = 20;
+        let global_state = CompositorGlobalState::new(config);
+        if global_state.keyboard.is_none() {
+            eprintln!("skipping: no keyboard without libxkbcommon");
+            return;
+        }
+        let global_state = Arc::new(RwLock::new(global_state));
+        let (sender, mut events) = mpsc::channel(SEAT_EVENT_BUFFER);
+        {
+            let mut global_state = global_state.write().unwrap();
+            global_state.seat_events.insert(1, sender);
+            global_state.set_keyboard_focus(Some((1, 10)));
+        }
+        tokio::spawn(repeat_keys(global_state.clone()));
+        tokio::task::yield_now().await;
+
+        global_state.write().unwrap().keyboard_key(KEY_A, true);
+        tokio::time::sleep(Duration::from_millis(499)).await;
+        assert_eq!(repeats(&mut events), 0, "repeated before the delay");
+        // The first repeat comes after the delay, then one every 50ms; stop
+        // between ticks so none is racing the check
+        tokio::time::sleep(Duration::from_millis(111)).await;
+        assert_eq!(repeats(&mut events), 3);
+
+        global_state.write().unwrap().keyboard_key(KEY_A, false);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(repeats(&mut events), 0, "repeated after release");
+    }
+}
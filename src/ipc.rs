@@ -0,0 +1,370 @@
+use crate::{
+    CompositorGlobalState,
+    accounting::{SurfaceStats, UsageSnapshot},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{
+        UnixListener, UnixStream,
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    sync::broadcast::error::RecvError,
+};
+use tracing::{debug, warn};
+
+/// Longest request line accepted; a peer sending more is disconnected.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// A request on the IPC socket, sent as one JSON object per line.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum IpcRequest {
+    Clients,
+    /// Per-surface commit statistics.
+    Stats,
+    /// Turns the connection into a stream of [`IpcEvent`]s; no further
+    /// requests are read from it.
+    Subscribe,
+    /// Moves the seat's pointer to global coordinates.
+    PointerMotion {
+        x: f64,
+        y: f64,
+    },
+    /// Presses or releases a button, given as a Linux input event code
+    /// (`BTN_LEFT` is 272).
+    PointerButton {
+        button: u32,
+        pressed: bool,
+    },
+    /// Scrolls by a number of pixels along either axis.
+    PointerAxis {
+        #[serde(default)]
+        vertical: f64,
+        #[serde(default)]
+        horizontal: f64,
+    },
+    /// Presses or releases a key, given as a Linux input event code
+    /// (`KEY_A` is 30).
+    Key {
+        key: u32,
+        pressed: bool,
+    },
+    /// Attaches or detaches the touch device.
+    TouchDevice {
+        present: bool,
+    },
+    /// Touch point requests take global coordinates; each is sent to
+    /// clients as its own frame.
+    TouchDown {
+        id: i32,
+        x: f64,
+        y: f64,
+    },
+    TouchMotion {
+        id: i32,
+        x: f64,
+        y: f64,
+    },
+    TouchUp {
+        id: i32,
+    },
+    TouchCancel,
+}
+
+/// The reply to an [`IpcRequest`], also one JSON object per line.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpcResponse {
+    Clients(Vec<UsageSnapshot>),
+    Stats(Vec<SurfaceStats>),
+    Subscribed,
+    Event(IpcEvent),
+    /// The request took effect.
+    Done,
+    Error(String),
+}
+
+/// Something that happened in the compositor, streamed to subscribers.
+///
+/// Only client lifetimes are reported so far; there are no windows,
+/// workspaces or outputs yet.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpcEvent {
+    ClientConnected { client_id: u64, pid: Option<i32> },
+    ClientDisconnected { client_id: u64 },
+}
+
+/// Where the IPC socket lives: the user's runtime directory, which only
+/// they can reach, since the socket can inject input into any client.
+pub fn socket_path() -> anyhow::Result<PathBuf> {
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .ok_or_else(|| anyhow::anyhow!("XDG_RUNTIME_DIR is not set"))?;
+    Ok(Path::new(&runtime_dir).join("way-too-far-ipc.sock"))
+}
+
+/// Binds the IPC socket at `path`, readable and writable by the owner only.
+///
+/// The socket is created under a restrictive umask rather than chmodded
+/// afterwards, so it is never reachable by anyone else, even briefly. The
+/// umask is process-wide, so call this before spawning anything that
+/// creates files.
+pub fn bind(path: &Path) -> anyhow::Result<UnixListener> {
+    let _ = std::fs::remove_file(path);
+    // SAFETY: umask only swaps the process's file mode creation mask
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    // SAFETY: as above, restoring the previous mask
+    unsafe { libc::umask(umask) };
+    Ok(listener?)
+}
+
+/// Accepts IPC connections until the listener fails. Connections from other
+/// users are closed straight away.
+pub async fn serve_ipc(listener: UnixListener, global_state: Arc<RwLock<CompositorGlobalState>>) {
+    let uid = unsafe { libc::getuid() };
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => match stream.peer_cred() {
+                Ok(cred) if cred.uid() == uid => {
+                    tokio::spawn(handle_ipc_connection(stream, global_state.clone()));
+                }
+                Ok(cred) => warn!("Refused IPC connection from uid {}", cred.uid()),
+                Err(e) => warn!("Refused IPC connection without credentials: {}", e),
+            },
+            Err(e) => {
+                warn!("Failed to accept IPC connection: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+async fn handle_ipc_connection(
+    stream: UnixStream,
+    global_state: Arc<RwLock<CompositorGlobalState>>,
+) {
+    debug!("New IPC connection");
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    while let Some(line) = read_request_line(&mut reader).await {
+        let response = match serde_json::from_str::<IpcRequest>(&line) {
+            Ok(IpcRequest::Subscribe) => {
+                stream_events(writer, &global_state).await;
+                return;
+            }
+            Ok(request) => handle_ipc_request(request, &global_state),
+            Err(e) => IpcResponse::Error(format!("Invalid request: {}", e)),
+        };
+        if write_response(&mut writer, &response).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Reads one request line without its line ending, or `None` once the peer
+/// disconnects or sends a line longer than [`MAX_REQUEST_SIZE`].
+async fn read_request_line(reader: &mut BufReader<OwnedReadHalf>) -> Option<String> {
+    let mut line = Vec::new();
+    let read = (&mut *reader)
+        .take(MAX_REQUEST_SIZE as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .await
+        .ok()?;
+    if read == 0 {
+        return None;
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    } else if line.len() > MAX_REQUEST_SIZE {
+        warn!(
+            "Dropping IPC connection over a request line of more than {} bytes",
+            MAX_REQUEST_SIZE
+        );
+        return None;
+    }
+    String::from_utf8(line).ok()
+}
+
+async fn write_response(writer: &mut OwnedWriteHalf, response: &IpcResponse) -> anyhow::Result<()> {
+    let mut reply = serde_json::to_vec(response)?;
+    reply.push(b'\n');
+    writer.write_all(&reply).await?;
+    Ok(())
+}
+
+/// Writes every event to a subscriber until it disconnects.
+async fn stream_events(mut writer: OwnedWriteHalf, global_state: &RwLock<CompositorGlobalState>) {
+    let subscription = global_state
+        .read()
+        .ok()
+        .map(|global_state| global_state.events.subscribe());
+    let Some(mut events) = subscription else {
+        let error = IpcResponse::Error("Global state lock poisoned".to_string());
+        write_response(&mut writer, &error).await.ok();
+        return;
+    };
+    if write_response(&mut writer, &IpcResponse::Subscribed)
+        .await
+        .is_err()
+    {
+        return;
+    }
+    loop {
+        let response = match events.recv().await {
+            Ok(event) => IpcResponse::Event(event),
+            Err(RecvError::Lagged(missed)) => {
+                IpcResponse::Error(format!("Subscriber fell behind, {} events dropped", missed))
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if write_response(&mut writer, &response).await.is_err() {
+            debug!("IPC subscriber disconnected");
+            return;
+        }
+    }
+}
+
+fn handle_ipc_request(
+    request: IpcRequest,
+    global_state: &RwLock<CompositorGlobalState>,
+) -> IpcResponse {
+    match request {
+        IpcRequest::Subscribe => IpcResponse::Error("Subscribe must be sent alone".to_string()),
+        IpcRequest::Clients => with_read(global_state, |global_state| {
+            let mut clients = global_state
+                .clients
+                .iter()
+                .map(|(id, usage)| usage.snapshot(*id))
+                .collect::<Vec<_>>();
+            clients.sort_by_key(|client| client.client_id);
+            IpcResponse::Clients(clients)
+        }),
+        IpcRequest::Stats => with_read(global_state, |global_state| {
+            let mut surfaces = global_state
+                .clients
+                .iter()
+                .flat_map(|(id, usage)| usage.surface_stats(*id))
+                .collect::<Vec<_>>();
+            surfaces.sort_by_key(|surface| (surface.client_id, surface.surface_id));
+            IpcResponse::Stats(surfaces)
+        }),
+        IpcRequest::PointerMotion { x, y } => with_write(global_state, |global_state| {
+            global_state.pointer_motion(x, y)
+        }),
+        IpcRequest::PointerButton { button, pressed } => with_write(global_state, |global_state| {
+            global_state.pointer_button(button, pressed)
+        }),
+        IpcRequest::PointerAxis {
+            vertical,
+            horizontal,
+        } => with_write(global_state, |global_state| {
+            global_state.pointer_axis(vertical, horizontal)
+        }),
+        IpcRequest::Key { key, pressed } => with_write(global_state, |global_state| {
+            global_state.keyboard_key(key, pressed)
+        }),
+        IpcRequest::TouchDevice { present } => with_write(global_state, |global_state| {
+            global_state.set_touch_present(present)
+        }),
+        IpcRequest::TouchDown { id, x, y } => with_write(global_state, |global_state| {
+            global_state.touch_down(id, x, y)
+        }),
+        IpcRequest::TouchMotion { id, x, y } => with_write(global_state, |global_state| {
+            global_state.touch_motion(id, x, y)
+        }),
+        IpcRequest::TouchUp { id } => {
+            with_write(global_state, |global_state| global_state.touch_up(id))
+        }
+        IpcRequest::TouchCancel => with_write(global_state, CompositorGlobalState::touch_cancel),
+    }
+}
+
+fn with_read(
+    global_state: &RwLock<CompositorGlobalState>,
+    f: impl FnOnce(&CompositorGlobalState) -> IpcResponse,
+) -> IpcResponse {
+    match global_state.read() {
+        Ok(global_state) => f(&global_state),
+        Err(_) => IpcResponse::Error("Global state lock poisoned".to_string()),
+    }
+}
+
+fn with_write(
+    global_state: &RwLock<CompositorGlobalState>,
+    f: impl FnOnce(&mut CompositorGlobalState),
+) -> IpcResponse {
+    match global_state.write() {
+        Ok(mut global_state) => {
+            f(&mut global_state);
+            IpcResponse::Done
+        }
+        Err(_) => IpcResponse::Error("Global state lock poisoned".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::Config;
+    use std::os::unix::fs::PermissionsExt;
+
+    async fn serve() -> (tempfile::TempDir, UnixStream) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ipc.sock");
+        let listener = bind(&path).unwrap();
+        let global_state = Arc::new(RwLock::new(CompositorGlobalState::new(Config::default())));
+        tokio::spawn(serve_ipc(listener, global_state));
+        let stream = UnixStream::connect(&path).await.unwrap();
+        (dir, stream)
+    }
+
+    #[tokio::test]
+    async fn requests_are_answered_line_by_line() {
+        let (_dir, mut stream) = serve().await;
+        stream
+            .write_all(b"{\"command\": \"clients\"}\r\n{\"command\": \"stats\"}\n")
+            .await
+            .unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        assert_eq!(
+            lines.next_line().await.unwrap().as_deref(),
+            Some(r#"{"clients":[]}"#)
+        );
+        assert_eq!(
+            lines.next_line().await.unwrap().as_deref(),
+            Some(r#"{"stats":[]}"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_request_line_is_dropped() {
+        let (_dir, mut stream) = serve().await;
+        stream
+            .write_all(&[b'a'; MAX_REQUEST_SIZE + 1])
+            .await
+            .unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.unwrap();
+        assert!(reply.is_empty());
+    }
+
+    #[tokio::test]
+    async fn socket_is_private_to_its_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ipc.sock");
+        let _listener = bind(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,845 @@
+use arc_swap::ArcSwap;
+use bytes::BytesMut;
+use futures::{FutureExt, lock::Mutex};
+use sendfd::{RecvWithFd, SendWithFd};
+use std::{
+    any::Any,
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    os::fd::{AsRawFd, OwnedFd},
+    panic::AssertUnwindSafe,
+    path::PathBuf,
+    sync::{Arc, PoisonError, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{UnixListener, UnixStream},
+    sync::{broadcast, mpsc},
+};
+use tracing::{Instrument, debug, debug_span, error, info_span, warn};
+
+use crate::{
+    accounting::ClientUsage,
+    capture::Capture,
+    config::{Config, LimitsConfig, PolicyConfig},
+    input::{
+        Keyboard, KeyboardEvent, Pointer, PointerEvent, SEAT_EVENT_BUFFER, Scene, SeatEvent, Touch,
+    },
+    ipc::IpcEvent,
+    throttle::{Throttle, ThrottleDecision},
+    wl_buffer::BufferState,
+    wl_display::WlDisplayError,
+    wl_keyboard::ClientKeyboard,
+    wl_pointer::ClientPointer,
+    wl_seat::{SeatState, WL_SEAT_VERSION, WlSeatCapability},
+    wl_shm_pool::ShmPool,
+    wl_surface::SurfaceState,
+    wl_touch::ClientTouch,
+    xdg_wm_base::XdgWmBaseState,
+};
+
+pub mod accounting;
+pub mod autostart;
+pub mod capture;
+pub mod config;
+pub mod convert;
+pub mod input;
+pub mod ipc;
+mod logging;
+pub mod metrics;
+pub mod session;
+pub mod sigbus;
+pub mod texture;
+pub mod throttle;
+pub mod utils;
+pub mod wire;
+pub mod wl_buffer;
+pub mod wl_callback;
+pub mod wl_compositor;
+pub mod wl_display;
+pub mod wl_keyboard;
+pub mod wl_output;
+pub mod wl_pointer;
+pub mod wl_region;
+pub mod wl_registry;
+pub mod wl_seat;
+pub mod wl_shm;
+pub mod wl_shm_pool;
+pub mod wl_surface;
+pub mod wl_touch;
+pub mod xdg_wm_base;
+pub mod xkb;
+
+/// Bytes requested from the socket per read; the buffer grows if a message spans reads.
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// Object ids from here up are allocated by the server, never by clients.
+const SERVER_ID_START: u32 = 0xff00_0000;
+
+/// Queued events are written early once this many bytes are pending.
+const MAX_PENDING_EVENT_BYTES: usize = 64 * 1024;
+
+/// Fds sent per sendmsg at most, matching what libwayland clients receive.
+const MAX_FDS_OUT: usize = 28;
+
+/// Events an IPC subscriber can fall behind by before missing some.
+const IPC_EVENT_BUFFER: usize = 256;
+
+/// State shared by every client connection.
+///
+/// Locking hierarchy: the global state `RwLock` is taken first and only for
+/// the duration of a synchronous read or update. It must never be held across
+/// an `.await` (the std guard isn't `Send`, so spawned tasks can't), which
+/// means event writes always happen after it has been released. Per-object
+/// locks such as shm pool mutexes are only taken without it held.
+pub struct CompositorGlobalState {
+    /// Advertised globals as `(name, interface, version)`.
+    ///
+    /// Clients keep their own handle and load a snapshot without taking any
+    /// lock; adding a global goes through the write lock and swaps
+    /// in a new list.
+    globals: Arc<ArcSwap<Vec<(u32, GlobalInterface, u32)>>>,
+    next_global_name: u32,
+    pub config: Config,
+    /// Resource usage of every connected client, keyed by client id.
+    pub clients: HashMap<u64, Arc<ClientUsage>>,
+    next_client_id: u64,
+    /// Events for IPC subscribers; sending with none subscribed is a no-op.
+    pub events: broadcast::Sender<IpcEvent>,
+    pub seat: SeatState,
+    pub pointer: Pointer,
+    /// `None` if no keymap could be compiled, in which case the seat has no
+    /// keyboard.
+    pub keyboard: Option<Keyboard>,
+    pub touch: Touch,
+    pub scene: Scene,
+    /// Queues of seat input for each client, keyed by client id.
+    seat_events: HashMap<u64, mpsc::Sender<SeatEvent>>,
+}
+impl CompositorGlobalState {
+    pub fn new(config: Config) -> Self {
+        let mut global_state = CompositorGlobalState {
+            config,
+            ..Default::default()
+        };
+        match Keyboard::new(&global_state.config.keyboard) {
+            Ok(keyboard) => {
+                global_state.keyboard = Some(keyboard);
+                global_state.seat.capabilities |= WlSeatCapability::Keyboard as u32;
+                global_state.seat.capabilities_ever |= WlSeatCapability::Keyboard as u32;
+            }
+            Err(e) => warn!("Seat has no keyboard: {}", e),
+        }
+        global_state
+    }
+
+    /// Advertises a new global to clients that create a registry from now on.
+    fn add_global(&mut self, interface: GlobalInterface, version: u32) -> u32 {
+        let name = self.next_global_name;
+        self.next_global_name += 1;
+        let mut globals = Vec::clone(&self.globals.load());
+        globals.push((name, interface, version));
+        self.globals.store(Arc::new(globals));
+        name
+    }
+}
+impl Default for CompositorGlobalState {
+    fn default() -> Self {
+        let mut global_state = CompositorGlobalState {
+            config: Config::default(),
+            clients: HashMap::new(),
+            next_client_id: 1,
+            events: broadcast::channel(IPC_EVENT_BUFFER).0,
+            seat: SeatState::default(),
+            pointer: Pointer::default(),
+            keyboard: None,
+            touch: Touch::default(),
+            scene: Scene::default(),
+            seat_events: HashMap::new(),
+            globals: Arc::new(ArcSwap::from_pointee(Vec::new())),
+            next_global_name: 1,
+        };
+        global_state.add_global(GlobalInterface::WlShm, 1);
+        global_state.add_global(GlobalInterface::WlCompositor, 6);
+        global_state.add_global(GlobalInterface::XdgWmBase, 7);
+        global_state.add_global(GlobalInterface::WlSeat, WL_SEAT_VERSION);
+        global_state
+    }
+}
+
+pub struct CompositorClientState<'a> {
+    stream: &'a mut UnixStream,
+    object_registry: HashMap<u32, WaylandObject>,
+    global_state: Arc<RwLock<CompositorGlobalState>>,
+    globals: Arc<ArcSwap<Vec<(u32, GlobalInterface, u32)>>>,
+    outgoing: BytesMut,
+    /// Fds for events in `outgoing`, sent along with it.
+    outgoing_fds: Vec<OwnedFd>,
+    client_id: u64,
+    usage: Arc<ClientUsage>,
+    limits: LimitsConfig,
+    throttle: Throttle,
+    ping_timeout: Duration,
+    serial: u32,
+    capture: Option<Capture>,
+    policy: PolicyConfig,
+    /// Resolved from the peer pid, for the global policy.
+    executable: Option<PathBuf>,
+    seat_events: mpsc::Receiver<SeatEvent>,
+    pointer: ClientPointer,
+    keyboard: ClientKeyboard,
+    touch: ClientTouch,
+}
+impl<'a> CompositorClientState<'a> {
+    pub fn new(
+        stream: &'a mut UnixStream,
+        global_state: Arc<RwLock<CompositorGlobalState>>,
+    ) -> Self {
+        let mut object_registry = HashMap::new();
+        object_registry.insert(1, WaylandObject::WlDisplay);
+        let usage = Arc::new(ClientUsage::new(
+            stream.peer_cred().ok().and_then(|cred| cred.pid()),
+        ));
+        let (seat_sender, seat_events) = mpsc::channel(SEAT_EVENT_BUFFER);
+        let (client_id, limits, globals, capture_dir, policy, ping_timeout) = {
+            // A panic in another client's handler mustn't leave new clients
+            // without globals or policy
+            let mut global_state = global_state.write().unwrap_or_else(PoisonError::into_inner);
+            let client_id = global_state.next_client_id;
+            global_state.next_client_id += 1;
+            global_state.clients.insert(client_id, usage.clone());
+            global_state.seat_events.insert(client_id, seat_sender);
+            let _ = global_state.events.send(IpcEvent::ClientConnected {
+                client_id,
+                pid: usage.pid(),
+            });
+            (
+                client_id,
+                global_state.config.limits.clone(),
+                global_state.globals.clone(),
+                global_state.config.capture.dir.clone(),
+                global_state.config.policy.clone(),
+                Duration::from_millis(global_state.config.ping.timeout_ms),
+            )
+        };
+        let executable = usage
+            .pid()
+            .and_then(|pid| std::fs::read_link(format!("/proc/{}/exe", pid)).ok());
+        let capture = capture_dir.and_then(|dir| match Capture::create(&dir, client_id) {
+            Ok(capture) => Some(capture),
+            Err(e) => {
+                warn!(
+                    "Failed to start protocol capture in {}: {}",
+                    dir.display(),
+                    e
+                );
+                None
+            }
+        });
+        CompositorClientState {
+            object_registry,
+            stream,
+            global_state,
+            globals,
+            outgoing: BytesMut::new(),
+            outgoing_fds: Vec::new(),
+            client_id,
+            usage,
+            throttle: Throttle::new(limits.clone()),
+            limits,
+            ping_timeout,
+            serial: 0,
+            capture,
+            policy,
+            executable,
+            seat_events,
+            pointer: ClientPointer::default(),
+            keyboard: ClientKeyboard::default(),
+            touch: ClientTouch::default(),
+        }
+    }
+
+    /// Whether the global policy lets this client see `interface`.
+    fn global_allowed(&self, interface: GlobalInterface) -> bool {
+        self.policy
+            .allows(interface.as_str(), self.executable.as_deref())
+    }
+
+    fn next_serial(&mut self) -> u32 {
+        self.serial = self.serial.wrapping_add(1);
+        self.serial
+    }
+}
+
+impl Drop for CompositorClientState<'_> {
+    fn drop(&mut self) {
+        // Still clean up after a panic elsewhere poisoned the lock
+        let mut global_state = self
+            .global_state
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        global_state.clients.remove(&self.client_id);
+        global_state.seat_events.remove(&self.client_id);
+        global_state.scene.remove_client(self.client_id);
+        global_state.update_focus();
+        let _ = global_state.events.send(IpcEvent::ClientDisconnected {
+            client_id: self.client_id,
+        });
+    }
+}
+
+/// Extracts the message from a caught panic payload.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("non-string panic payload")
+}
+
+enum WaylandObject {
+    WlDisplay,
+    WlRegistry,
+
+    XdgWmBase(XdgWmBaseState),
+    WlShmPool(Arc<Mutex<ShmPool>>),
+    WlCompositor,
+
+    WlCallback,
+    WlShm,
+    WlBuffer(BufferState),
+    WlSurface(SurfaceState),
+    WlRegion,
+    #[allow(dead_code)]
+    WlOutput,
+    WlSeat {
+        version: u32,
+    },
+    WlPointer {
+        version: u32,
+    },
+    WlKeyboard {
+        version: u32,
+    },
+    WlTouch {
+        version: u32,
+    },
+}
+impl WaylandObject {
+    fn as_str(&self) -> &'static str {
+        match self {
+            WaylandObject::WlDisplay => "wl_display",
+            WaylandObject::WlRegistry => "wl_registry",
+            WaylandObject::WlCallback => "wl_callback",
+            WaylandObject::XdgWmBase(_) => "xdg_wm_base",
+            WaylandObject::WlShmPool(_) => "wl_shm_pool",
+            WaylandObject::WlShm => "wl_shm",
+            WaylandObject::WlBuffer(_) => "wl_buffer",
+            WaylandObject::WlCompositor => "wl_compositor",
+            WaylandObject::WlSurface(_) => "wl_surface",
+            WaylandObject::WlRegion => "wl_region",
+            WaylandObject::WlOutput => "wl_output",
+            WaylandObject::WlSeat { .. } => "wl_seat",
+            WaylandObject::WlPointer { .. } => "wl_pointer",
+            WaylandObject::WlKeyboard { .. } => "wl_keyboard",
+            WaylandObject::WlTouch { .. } => "wl_touch",
+        }
+    }
+}
+/// An interface advertised through wl_registry.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum GlobalInterface {
+    WlShm,
+    WlCompositor,
+    XdgWmBase,
+    WlSeat,
+}
+impl GlobalInterface {
+    fn as_str(self) -> &'static str {
+        match self {
+            GlobalInterface::WlShm => "wl_shm",
+            GlobalInterface::WlCompositor => "wl_compositor",
+            GlobalInterface::XdgWmBase => "xdg_wm_base",
+            GlobalInterface::WlSeat => "wl_seat",
+        }
+    }
+
+    /// A fresh object for a client binding this global at `version`.
+    fn bind(self, version: u32) -> WaylandObject {
+        match self {
+            GlobalInterface::WlShm => WaylandObject::WlShm,
+            GlobalInterface::WlCompositor => WaylandObject::WlCompositor,
+            GlobalInterface::XdgWmBase => WaylandObject::XdgWmBase(XdgWmBaseState::default()),
+            GlobalInterface::WlSeat => WaylandObject::WlSeat { version },
+        }
+    }
+}
+
+impl Display for WaylandObject {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl<'a> CompositorClientState<'a> {
+    /// Queues an event; it reaches the client on the next [`Self::flush`].
+    async fn send_message(
+        &mut self,
+        object_id: u32,
+        op_code: u16,
+        args: &[u8],
+    ) -> anyhow::Result<()> {
+        wire::encode_message(&mut self.outgoing, object_id, op_code, args)?;
+        self.flush_if_full().await
+    }
+
+    /// Starts an event encoded straight into the outgoing queue; follow it
+    /// with [`Self::flush_if_full`] once the writer is dropped.
+    fn event(&mut self, object_id: u32, op_code: u16) -> wire::EventWriter<'_> {
+        wire::EventWriter::new(&mut self.outgoing, object_id, op_code)
+    }
+
+    /// Queues an fd argument; call it before writing the event it belongs
+    /// to, as fds go out ahead of the bytes they are sent with.
+    async fn attach_fd(&mut self, fd: OwnedFd) -> anyhow::Result<()> {
+        if self.outgoing_fds.len() >= MAX_FDS_OUT {
+            self.flush().await?;
+        }
+        self.outgoing_fds.push(fd);
+        Ok(())
+    }
+
+    /// Writes queued events early if enough have built up.
+    async fn flush_if_full(&mut self) -> anyhow::Result<()> {
+        if self.outgoing.len() >= MAX_PENDING_EVENT_BYTES {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Rejects a request whose opcode the object's interface doesn't define.
+    async fn invalid_method(&mut self, object_id: u32, op_code: u16) -> anyhow::Result<()> {
+        let interface = self
+            .object_registry
+            .get(&object_id)
+            .map_or("unknown", WaylandObject::as_str);
+        let message = format!("invalid method {} for {}@{}", op_code, interface, object_id);
+        self.send_wl_display_error(object_id, WlDisplayError::InvalidMethod as u32, &message)
+            .await?;
+        anyhow::bail!("Client sent {}", message);
+    }
+
+    /// Registers an object created by a request's new_id argument.
+    ///
+    /// Like libwayland, an id that is null, already in use or outside the
+    /// client's range makes the request invalid.
+    async fn insert_new_object(
+        &mut self,
+        object_id: u32,
+        new_id: u32,
+        object: WaylandObject,
+    ) -> anyhow::Result<()> {
+        if new_id == 0 || new_id >= SERVER_ID_START || self.object_registry.contains_key(&new_id) {
+            let message = format!("invalid new id {}", new_id);
+            self.send_wl_display_error(object_id, WlDisplayError::InvalidMethod as u32, &message)
+                .await?;
+            anyhow::bail!("Client sent {}", message);
+        }
+        self.object_registry.insert(new_id, object);
+        Ok(())
+    }
+
+    /// Checks that an object argument names a live object of the expected
+    /// interface, or is null where the request allows it.
+    async fn check_object_argument(
+        &mut self,
+        object_id: u32,
+        argument: u32,
+        nullable: bool,
+        is_expected: fn(&WaylandObject) -> bool,
+    ) -> anyhow::Result<()> {
+        let valid = match self.object_registry.get(&argument) {
+            Some(object) => is_expected(object),
+            None => argument == 0 && nullable,
+        };
+        if !valid {
+            let message = format!(
+                "invalid object argument {} for object {}",
+                argument, object_id
+            );
+            self.send_wl_display_error(object_id, WlDisplayError::InvalidMethod as u32, &message)
+                .await?;
+            anyhow::bail!("Client sent {}", message);
+        }
+        Ok(())
+    }
+
+    /// Sends events for `first` and any other queued seat input, then flushes.
+    ///
+    /// Gaining focus or a key or button press pings the client, so a user
+    /// waiting on an unresponsive client learns about it.
+    async fn dispatch_seat_events(&mut self, mut first: Option<SeatEvent>) -> anyhow::Result<()> {
+        let mut ping = false;
+        while let Some(event) = first.take().or_else(|| self.seat_events.try_recv().ok()) {
+            ping |= matches!(
+                event,
+                SeatEvent::Keyboard(
+                    KeyboardEvent::Enter { .. } | KeyboardEvent::Key { pressed: true, .. }
+                ) | SeatEvent::Pointer(PointerEvent::Button { pressed: true, .. })
+            );
+            match event {
+                SeatEvent::Capabilities(capabilities) => {
+                    self.send_wl_seat_capabilities(capabilities).await?
+                }
+                SeatEvent::Pointer(event) => self.handle_pointer_event(event).await?,
+                SeatEvent::Keyboard(event) => self.handle_keyboard_event(event).await?,
+                SeatEvent::Touch(event) => self.handle_touch_event(event).await?,
+            }
+        }
+        if ping {
+            self.ping_xdg_wm_bases().await?;
+        }
+        self.flush().await
+    }
+
+    /// Writes all queued events to the socket in one go.
+    pub async fn flush(&mut self) -> anyhow::Result<()> {
+        if self.outgoing.is_empty() {
+            return Ok(());
+        }
+        if let Some(capture) = &mut self.capture {
+            capture.record_events(&self.outgoing);
+        }
+        let mut sent = 0;
+        if !self.outgoing_fds.is_empty() {
+            // The fds ride on the first sendmsg, so they arrive no later than
+            // the events that take them
+            let fds = self
+                .outgoing_fds
+                .iter()
+                .map(AsRawFd::as_raw_fd)
+                .collect::<Vec<_>>();
+            sent = loop {
+                self.stream.writable().await?;
+                match self.stream.send_with_fd(&self.outgoing, &fds) {
+                    Ok(sent) => break sent,
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e.into()),
+                }
+            };
+            self.outgoing_fds.clear();
+        }
+        self.stream.write_all(&self.outgoing[sent..]).await?;
+        self.outgoing.clear();
+        Ok(())
+    }
+
+    pub async fn handle_message(
+        &mut self,
+        object_id: u32,
+        op_code: u16,
+        arg_bytes: &[u8],
+        fds: &mut VecDeque<i32>,
+    ) -> anyhow::Result<()> {
+        if let Some(object) = self.object_registry.get_mut(&object_id) {
+            let result = match object {
+                WaylandObject::WlDisplay => {
+                    self.handle_wl_display_message(object_id, op_code, arg_bytes)
+                        .await
+                }
+                WaylandObject::WlRegistry => {
+                    self.handle_wl_registry_message(object_id, op_code, arg_bytes)
+                        .await
+                }
+                WaylandObject::WlCallback => {
+                    self.handle_wl_callback_message(object_id, op_code).await
+                }
+                WaylandObject::WlShm => {
+                    self.handle_wl_shm_message(object_id, op_code, arg_bytes, fds)
+                        .await
+                }
+                WaylandObject::WlShmPool(_pool) => {
+                    self.handle_wl_shm_pool_message(object_id, op_code, arg_bytes)
+                        .await
+                }
+                WaylandObject::WlBuffer(_buffer_data) => {
+                    self.handle_wl_buffer_message(object_id, op_code).await
+                }
+
+                WaylandObject::WlCompositor => {
+                    self.handle_wl_compositor_message(object_id, op_code, arg_bytes)
+                        .await
+                }
+
+                WaylandObject::WlSurface(_surface) => {
+                    self.handle_wl_surface_message(object_id, op_code, arg_bytes)
+                        .await
+                }
+
+                WaylandObject::WlRegion => {
+                    self.handle_wl_region_message(object_id, op_code, arg_bytes)
+                        .await
+                }
+
+                WaylandObject::XdgWmBase(_wm_base) => {
+                    self.handle_xdg_wm_base_message(object_id, op_code, arg_bytes)
+                        .await
+                }
+
+                WaylandObject::WlOutput => {
+                    self.handle_wl_output_message(object_id, op_code, arg_bytes)
+                        .await
+                }
+                &mut WaylandObject::WlSeat { version } => {
+                    self.handle_wl_seat_message(object_id, op_code, arg_bytes, version)
+                        .await
+                }
+                &mut WaylandObject::WlPointer { version } => {
+                    self.handle_wl_pointer_message(object_id, op_code, arg_bytes, version)
+                        .await
+                }
+                &mut WaylandObject::WlKeyboard { version } => {
+                    self.handle_wl_keyboard_message(object_id, op_code, version)
+                        .await
+                }
+                &mut WaylandObject::WlTouch { version } => {
+                    self.handle_wl_touch_message(object_id, op_code, version)
+                        .await
+                }
+            };
+            if let Err(e) = result {
+                if let Some(argument_error) = e.downcast_ref::<wire::ArgumentError>() {
+                    // libwayland reports undecodable arguments as invalid_method
+                    let interface = self
+                        .object_registry
+                        .get(&object_id)
+                        .map_or("unknown", WaylandObject::as_str);
+                    let message = format!(
+                        "invalid arguments for {}@{}.{}: {}",
+                        interface, object_id, op_code, argument_error
+                    );
+                    self.send_wl_display_error(
+                        object_id,
+                        WlDisplayError::InvalidMethod as u32,
+                        &message,
+                    )
+                    .await?;
+                }
+                return Err(e);
+            }
+
+            self.usage.set_objects(self.object_registry.len());
+            if self.object_registry.len() > self.limits.max_objects {
+                self.send_wl_display_error(
+                    1,
+                    WlDisplayError::NoMemory as u32,
+                    "object limit exceeded",
+                )
+                .await?;
+                anyhow::bail!(
+                    "Client exceeded the limit of {} objects",
+                    self.limits.max_objects
+                );
+            }
+            Ok(())
+        } else {
+            warn!("Unknown object ID: {}", object_id);
+            if self.throttle.record_bad_request(Instant::now()) {
+                anyhow::bail!(
+                    "Misbehavior score {} exceeded the limit",
+                    self.throttle.score()
+                );
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Accepts client connections on `listener` until it fails, serving each on
+/// its own task, and repeats held keys meanwhile.
+pub async fn serve(
+    listener: UnixListener,
+    global_state: Arc<RwLock<CompositorGlobalState>>,
+) -> anyhow::Result<()> {
+    tokio::spawn(input::repeat_keys(global_state.clone()));
+    loop {
+        let (stream, _) = listener.accept().instrument(debug_span!("accept")).await?;
+        tokio::spawn(serve_client(stream, global_state.clone()));
+    }
+}
+
+/// Runs the protocol loop for one client connection until it closes or errors.
+pub async fn serve_client(
+    mut stream: UnixStream,
+    global_state: Arc<RwLock<CompositorGlobalState>>,
+) {
+    let mut client_state = CompositorClientState::new(&mut stream, global_state);
+    let span = info_span!(
+        "client",
+        client_id = client_state.client_id,
+        pid = client_state.usage.pid()
+    );
+    run_client(&mut client_state).instrument(span).await;
+}
+
+async fn run_client(client_state: &mut CompositorClientState<'_>) {
+    debug!("New client connected");
+    if client_state.stream.readable().await.is_err() {
+        error!("Failed to await readability on socket");
+        return;
+    }
+
+    let mut data = BytesMut::with_capacity(READ_CHUNK_SIZE);
+    let mut pending_fds = VecDeque::<i32>::new();
+
+    loop {
+        let filled = data.len();
+        data.resize(filled + READ_CHUNK_SIZE, 0);
+        let mut fds = [0; 10];
+        let result = client_state
+            .stream
+            .recv_with_fd(&mut data[filled..], &mut fds);
+
+        match result {
+            Ok((0, 0)) => {
+                warn!("Connection closed while reading");
+                return;
+            }
+            Ok((data_read, fds_read)) => {
+                data.truncate(filled + data_read);
+                if let Some(capture) = &mut client_state.capture
+                    && fds_read > 0
+                {
+                    capture.record_fds(&fds[..fds_read]);
+                }
+                for &fd in &fds[..fds_read] {
+                    pending_fds.push_back(fd);
+                }
+
+                loop {
+                    let message = match wire::decode_message(&mut data) {
+                        Ok(Some(message)) => message,
+                        Ok(None) => break,
+                        Err(e) => {
+                            error!("Error decoding message: {}", e);
+                            error!("Closing connection due to error.");
+                            client_state.flush().await.ok();
+                            client_state.stream.shutdown().await.ok();
+                            return;
+                        }
+                    };
+                    client_state.usage.record_message();
+                    if let Some(capture) = &mut client_state.capture {
+                        let interface = client_state
+                            .object_registry
+                            .get(&message.object_id)
+                            .map_or("unknown", WaylandObject::as_str);
+                        capture.record_request(interface, &message);
+                    }
+                    match client_state.throttle.check_request(Instant::now()) {
+                        ThrottleDecision::Allow => {}
+                        ThrottleDecision::Pause(duration) => {
+                            debug!("Throttling client for {:?}", duration);
+                            if let Err(e) = client_state.flush().await {
+                                warn!("Failed to write events to client: {}", e);
+                                return;
+                            }
+                            tokio::time::sleep(duration).await;
+                        }
+                        ThrottleDecision::Disconnect => {
+                            error!(
+                                "Disconnecting client over request rate, misbehavior score {}",
+                                client_state.throttle.score()
+                            );
+                            client_state.flush().await.ok();
+                            client_state.stream.shutdown().await.ok();
+                            return;
+                        }
+                    }
+                    // A panicking handler takes down only this client: its
+                    // objects are dropped with the client state and the
+                    // global registry entry is removed by Drop.
+                    let res = AssertUnwindSafe(client_state.handle_message(
+                        message.object_id,
+                        message.op_code,
+                        &message.args,
+                        &mut pending_fds,
+                    ))
+                    .catch_unwind()
+                    .instrument(debug_span!(
+                        "dispatch",
+                        object_id = message.object_id,
+                        op_code = message.op_code
+                    ))
+                    .await;
+                    let res = match res {
+                        Ok(res) => res,
+                        Err(payload) => {
+                            error!(
+                                client_id = client_state.client_id,
+                                object_id = message.object_id,
+                                op_code = message.op_code,
+                                panic = panic_message(payload.as_ref()),
+                                "Handler panicked, disconnecting client"
+                            );
+                            client_state.outgoing.clear();
+                            client_state.outgoing_fds.clear();
+                            client_state
+                                .send_wl_display_error(
+                                    1,
+                                    WlDisplayError::Implementation as u32,
+                                    "internal compositor error",
+                                )
+                                .await
+                                .ok();
+                            client_state.flush().await.ok();
+                            client_state.stream.shutdown().await.ok();
+                            return;
+                        }
+                    };
+                    if let Err(e) = res {
+                        error!("Error handling message: {}", e);
+                        error!("Closing connection due to error.");
+                        client_state.flush().await.ok();
+                        client_state.stream.shutdown().await.ok();
+                        return;
+                    }
+                }
+                if let Err(e) = client_state.dispatch_seat_events(None).await {
+                    warn!("Failed to write events to client: {}", e);
+                    return;
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                data.truncate(filled);
+                // Only an outstanding ping needs waking up for
+                let ping_deadline = client_state.ping_deadline();
+                let ping_expired =
+                    tokio::time::sleep_until(ping_deadline.unwrap_or_else(Instant::now).into());
+                // Input can arrive for a client that is idle on its socket
+                tokio::select! {
+                    readable = client_state.stream.readable() => {
+                        if readable.is_err() {
+                            error!("Failed to await readability on socket");
+                            return;
+                        }
+                    }
+                    Some(event) = client_state.seat_events.recv() => {
+                        if let Err(e) = client_state.dispatch_seat_events(Some(event)).await {
+                            warn!("Failed to write events to client: {}", e);
+                            return;
+                        }
+                    }
+                    _ = ping_expired, if ping_deadline.is_some() => {
+                        client_state.check_xdg_wm_base_pings();
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("Connection closed or error while reading: {}", e);
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,28 @@
+//! Logging helpers for per-frame request paths.
+//!
+//! Debug lines on attach, damage, frame and commit fire for every frame of
+//! every client. Release builds can compile them out entirely with the
+//! `release-max-level-info` feature; otherwise [`debug_sampled!`] keeps only
+//! one line in [`HOT_PATH_SAMPLE_RATE`] per call site.
+
+/// One in this many calls of a sampled call site is logged; about once a
+/// second for a client redrawing at 60 Hz.
+pub const HOT_PATH_SAMPLE_RATE: u32 = 60;
+
+/// Like `debug!`, but logs only one in [`HOT_PATH_SAMPLE_RATE`] calls from
+/// this call site.
+///
+/// The counter is only touched when debug logging is enabled, so a disabled
+/// call site costs a level check and nothing else.
+macro_rules! debug_sampled {
+    ($($arg:tt)+) => {{
+        use std::sync::atomic::{AtomicU32, Ordering};
+        static CALLS: AtomicU32 = AtomicU32::new(0);
+        if tracing::enabled!(tracing::Level::DEBUG)
+            && CALLS.fetch_add(1, Ordering::Relaxed) % $crate::logging::HOT_PATH_SAMPLE_RATE == 0
+        {
+            tracing::debug!($($arg)+);
+        }
+    }};
+}
+pub(crate) use debug_sampled;
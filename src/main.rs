@@ -1,191 +1,57 @@
-use futures::lock::{Mutex, MutexGuard};
-use memmap2::MmapMut;
-use sendfd::RecvWithFd;
 use std::{
-    collections::{HashMap, VecDeque},
-    fmt::Display,
-    sync::Arc,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
 };
-use tokio::{
-    io::AsyncWriteExt,
-    net::{UnixSocket, UnixStream},
+use tokio::net::UnixSocket;
+use tracing_subscriber::{filter::LevelFilter, prelude::*};
+use way_too_far::{
+    CompositorGlobalState, autostart, capture,
+    config::{Config, RuntimeConfig, RuntimeFlavor},
+    ipc, metrics, serve, session, sigbus,
 };
-use tracing::{debug, error, warn};
 
-use crate::{wl_buffer::BufferState, wl_surface::SurfaceState};
+fn main() -> anyhow::Result<()> {
+    init_tracing();
 
-mod utils;
-mod wl_buffer;
-mod wl_callback;
-mod wl_compositor;
-mod wl_display;
-mod wl_output;
-mod wl_region;
-mod wl_registry;
-mod wl_shm;
-mod wl_shm_pool;
-mod wl_surface;
-mod xdg_wm_base;
+    sigbus::install_sigbus_handler()?;
 
-struct CompositorGlobalState {
-    globals: Vec<(u32, WaylandObject, u32)>,
-}
-impl Default for CompositorGlobalState {
-    fn default() -> Self {
-        CompositorGlobalState {
-            globals: vec![
-                (1, WaylandObject::WlShm, 1),
-                (2, WaylandObject::WlCompositor, 6),
-                (3, WaylandObject::XdgWmBase, 7),
-            ],
-        }
-    }
-}
-
-struct CompositorClientState<'a> {
-    stream: &'a mut UnixStream,
-    object_registry: HashMap<u32, WaylandObject>,
-}
-impl<'a> CompositorClientState<'a> {
-    fn new(stream: &'a mut UnixStream) -> Self {
-        let mut object_registry = HashMap::new();
-        object_registry.insert(1, WaylandObject::WlDisplay);
-        CompositorClientState {
-            object_registry,
-            stream,
-        }
-    }
-}
-
-enum WaylandObject {
-    WlDisplay,
-    WlRegistry,
-
-    XdgWmBase,
-    WlShmPool(Arc<Mutex<MmapMut>>, i32),
-    WlCompositor,
-
-    WlCallback,
-    WlShm,
-    WlBuffer(BufferState),
-    WlSurface(SurfaceState),
-    WlRegion,
-    WlOutput,
-}
-impl WaylandObject {
-    fn as_str(&self) -> &'static str {
-        match self {
-            WaylandObject::WlDisplay => "wl_display",
-            WaylandObject::WlRegistry => "wl_registry",
-            WaylandObject::WlCallback => "wl_callback",
-            WaylandObject::XdgWmBase => "xdg_wm_base",
-            WaylandObject::WlShmPool(_, _) => "wl_shm_pool",
-            WaylandObject::WlShm => "wl_shm",
-            WaylandObject::WlBuffer(_) => "wl_buffer",
-            WaylandObject::WlCompositor => "wl_compositor",
-            WaylandObject::WlSurface(_) => "wl_surface",
-            WaylandObject::WlRegion => "wl_region",
-            WaylandObject::WlOutput => "wl_output",
-        }
+    let mut config = Config::load()?;
+    let replay_path = apply_args(&mut config.runtime, std::env::args().skip(1))?;
+    let runtime = build_runtime(&config.runtime)?;
+    match replay_path {
+        Some(path) => runtime.block_on(replay(config, path)),
+        None => runtime.block_on(run(config)),
     }
 }
-impl Display for WaylandObject {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.as_str())
-    }
-}
-
-impl<'a> CompositorClientState<'a> {
-    async fn send_message(
-        &mut self,
-        object_id: u32,
-        op_code: u16,
-        args: &[u8],
-    ) -> anyhow::Result<()> {
-        if self.stream.writable().await.is_err() {
-            error!("Failed to await writability on socket");
-            anyhow::bail!("Socket not writable");
-        }
-        self.stream.write_all(&object_id.to_le_bytes()).await?;
-        self.stream.write_all(&op_code.to_le_bytes()).await?;
-        self.stream
-            .write_all(&(8 + args.len() as u16).to_le_bytes())
-            .await?;
-        self.stream.write_all(args).await?;
-        Ok(())
-    }
-
-    async fn handle_message(
-        &mut self,
-        object_id: u32,
-        op_code: u16,
-        arg_bytes: &[u8],
-        fds: &mut VecDeque<i32>,
-        global_state: MutexGuard<'_, CompositorGlobalState>,
-    ) -> anyhow::Result<()> {
-        if let Some(object) = self.object_registry.get_mut(&object_id) {
-            match object {
-                WaylandObject::WlDisplay => {
-                    self.handle_wl_display_message(op_code, arg_bytes, global_state)
-                        .await?;
-                }
-                WaylandObject::WlRegistry => {
-                    self.handle_wl_registry_message(op_code, arg_bytes, global_state)
-                        .await?
-                }
-                WaylandObject::WlCallback => self.handle_wl_callback_message(op_code).await?,
-                WaylandObject::WlShm => {
-                    self.handle_wl_shm_message(object_id, op_code, arg_bytes, fds)
-                        .await?
-                }
-                WaylandObject::WlShmPool(_mmap, _fd) => {
-                    self.handle_wl_shm_pool_message(object_id, op_code, arg_bytes)
-                        .await?
-                }
-                WaylandObject::WlBuffer(_buffer_data) => {
-                    self.handle_wl_buffer_message(object_id, op_code).await?
-                }
-
-                WaylandObject::WlCompositor => {
-                    self.handle_wl_compositor_message(op_code, arg_bytes)
-                        .await?
-                }
 
-                WaylandObject::WlSurface(_surface) => {
-                    self.handle_wl_surface_message(object_id, op_code, arg_bytes)
-                        .await?
-                }
-
-                WaylandObject::WlRegion => {
-                    self.handle_wl_region_message(object_id, op_code, arg_bytes)
-                        .await?
-                }
-
-                WaylandObject::XdgWmBase => {
-                    self.handle_xdg_wm_base_message(object_id, op_code, arg_bytes)
-                        .await?
-                }
-
-                WaylandObject::WlOutput => {
-                    self.handle_wl_output_message(object_id, op_code, arg_bytes)
-                        .await?
-                }
-            }
-            Ok(())
-        } else {
-            warn!("Unknown object ID: {}", object_id);
-            Ok(())
-        }
+/// Replays a protocol capture instead of serving clients.
+async fn replay(mut config: Config, path: PathBuf) -> anyhow::Result<()> {
+    config.capture.dir = None;
+    let global_state = Arc::new(RwLock::new(CompositorGlobalState::new(config)));
+    let summary = capture::replay(&path, global_state).await?;
+    println!(
+        "Replayed {} requests: {} events captured, {} replayed, {} differ",
+        summary.requests,
+        summary.captured_events,
+        summary.replayed_events,
+        summary.mismatched_events
+    );
+    if let Some(error) = summary.error {
+        println!("Dispatch stopped early: {}", error);
     }
+    Ok(())
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::DEBUG)
-        .init();
-
-    let global_state_mutex = Arc::new(Mutex::new(CompositorGlobalState::default()));
+async fn run(config: Config) -> anyhow::Result<()> {
+    let metrics_listen = config.metrics.listen.clone();
+    let session_config = config.session.clone();
+    let autostart = config
+        .exec_once
+        .iter()
+        .chain(&config.exec)
+        .cloned()
+        .collect::<Vec<_>>();
+    let global_state = Arc::new(RwLock::new(CompositorGlobalState::new(config)));
 
     let socket_path = "/tmp/my-wayland-socket.sock";
     let _ = std::fs::remove_file(socket_path);
@@ -196,87 +62,97 @@ async fn main() -> anyhow::Result<()> {
     let listener = socket.listen(1024)?;
     println!("Listening on {:?}", socket_path);
 
-    loop {
-        let (mut stream, _) = listener.accept().await?;
-        let global_state_mutex = global_state_mutex.clone();
+    // Bound before anything is spawned, since binding swaps the process
+    // umask. Without a runtime directory there is nowhere private to put it
+    match ipc::socket_path() {
+        Ok(ipc_path) => {
+            let ipc_listener = ipc::bind(&ipc_path)?;
+            println!("Serving IPC on {:?}", ipc_path);
+            tokio::spawn(ipc::serve_ipc(ipc_listener, global_state.clone()));
+        }
+        Err(e) => tracing::warn!("IPC disabled: {}", e),
+    }
 
+    if session_config.export_environment {
+        let session_config = session_config.clone();
         tokio::spawn(async move {
-            debug!("New client connected");
-            let mut client_state = CompositorClientState::new(&mut stream);
-            if client_state.stream.readable().await.is_err() {
-                error!("Failed to await readability on socket");
-                return;
-            }
+            session::export_environment(&session_config, Path::new(socket_path)).await
+        });
+    }
 
-            let mut data = VecDeque::<u8>::new();
-            let mut pending_fds = VecDeque::<i32>::new();
+    if let Some(address) = metrics_listen {
+        let metrics_listener = tokio::net::TcpListener::bind(&address).await?;
+        println!("Serving metrics on {:?}", address);
+        tokio::spawn(metrics::serve_metrics(
+            metrics_listener,
+            global_state.clone(),
+        ));
+    }
 
-            loop {
-                let mut buffer = [0u8; 4096];
-                let mut fds = [0; 10];
-                let result = client_state.stream.recv_with_fd(&mut buffer, &mut fds);
+    for exec in autostart {
+        tokio::spawn(autostart::supervise(
+            exec,
+            session_config.clone(),
+            socket_path.into(),
+            global_state.clone(),
+        ));
+    }
 
-                match result {
-                    Ok((0, 0)) => {
-                        warn!("Connection closed while reading");
-                        return;
-                    }
-                    Ok((data_read, fds_read)) => {
-                        for byte in &buffer[..data_read] {
-                            data.push_back(*byte);
-                        }
-                        for &fd in &fds[..fds_read] {
-                            pending_fds.push_back(fd);
-                        }
+    serve(listener, global_state).await
+}
 
-                        while data.len() >= 8
-                            && data.len() >= u16::from_le_bytes([data[6], data[7]]) as usize
-                        {
-                            let object_id = u32::from_le_bytes([
-                                data.pop_front().unwrap(),
-                                data.pop_front().unwrap(),
-                                data.pop_front().unwrap(),
-                                data.pop_front().unwrap(),
-                            ]);
-                            let op_code = u16::from_le_bytes([
-                                data.pop_front().unwrap(),
-                                data.pop_front().unwrap(),
-                            ]) as usize;
-                            let message_length = u16::from_le_bytes([
-                                data.pop_front().unwrap(),
-                                data.pop_front().unwrap(),
-                            ]);
-                            let mut args_buffer = vec![0u8; message_length as usize - 8];
-                            (0..args_buffer.len()).for_each(|i| {
-                                args_buffer[i] = data.pop_front().unwrap();
-                            });
-                            let global_state = global_state_mutex.lock().await;
-                            let res = client_state
-                                .handle_message(
-                                    object_id,
-                                    op_code as u16,
-                                    &args_buffer,
-                                    &mut pending_fds,
-                                    global_state,
-                                )
-                                .await;
-                            if let Err(e) = res {
-                                error!("Error handling message: {}", e);
-                                error!("Closing connection due to error.");
-                                stream.shutdown().await.ok();
-                                return;
-                            }
-                        }
-                    }
-                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                        continue;
-                    }
-                    Err(e) => {
-                        warn!("Connection closed or error while reading: {}", e);
-                        return;
-                    }
+/// Applies command line overrides of the `[runtime]` config section, and
+/// returns the capture to replay if `--replay` was given.
+fn apply_args(
+    runtime: &mut RuntimeConfig,
+    mut args: impl Iterator<Item = String>,
+) -> anyhow::Result<Option<PathBuf>> {
+    let mut replay_path = None;
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow::anyhow!("Missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--runtime" => {
+                runtime.flavor = match value()?.as_str() {
+                    "current_thread" => RuntimeFlavor::CurrentThread,
+                    "multi_thread" => RuntimeFlavor::MultiThread,
+                    other => anyhow::bail!("Unknown runtime flavor: {}", other),
                 }
             }
-        });
+            "--worker-threads" => {
+                runtime.worker_threads = Some(
+                    value()?
+                        .parse()
+                        .map_err(|e| anyhow::anyhow!("Invalid --worker-threads: {}", e))?,
+                )
+            }
+            "--replay" => replay_path = Some(PathBuf::from(value()?)),
+            _ => anyhow::bail!("Unknown argument: {}", arg),
+        }
     }
+    Ok(replay_path)
+}
+
+fn build_runtime(config: &RuntimeConfig) -> anyhow::Result<tokio::runtime::Runtime> {
+    let mut builder = match config.flavor {
+        RuntimeFlavor::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+        RuntimeFlavor::MultiThread => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            if let Some(worker_threads) = config.worker_threads {
+                builder.worker_threads(worker_threads);
+            }
+            builder
+        }
+    };
+    Ok(builder.enable_all().build()?)
+}
+
+fn init_tracing() {
+    let fmt = tracing_subscriber::fmt::layer().with_filter(LevelFilter::DEBUG);
+    let registry = tracing_subscriber::registry().with(fmt);
+    #[cfg(feature = "console")]
+    let registry = registry.with(console_subscriber::spawn());
+    registry.init();
 }
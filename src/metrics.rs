@@ -0,0 +1,239 @@
+use crate::{CompositorGlobalState, accounting::UsageSnapshot};
+use std::{
+    fmt::Write,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, warn};
+
+/// Largest request head read from a scraper before answering.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// How long a scraper has to send its request head, and then to read the
+/// response, before the connection is dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves OpenMetrics text on `GET /metrics` until the listener fails.
+///
+/// Scrapers only ever send a bare request line and headers, so the server
+/// reads up to the end of the head, answers once and closes the connection.
+pub async fn serve_metrics(
+    listener: TcpListener,
+    global_state: Arc<RwLock<CompositorGlobalState>>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_metrics_connection(stream, global_state.clone()));
+            }
+            Err(e) => {
+                warn!("Failed to accept metrics connection: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+async fn handle_metrics_connection(
+    mut stream: TcpStream,
+    global_state: Arc<RwLock<CompositorGlobalState>>,
+) {
+    let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request_head(&mut stream)).await
+    {
+        Ok(Some(request)) => request,
+        Ok(None) => return,
+        Err(_) => {
+            debug!("Metrics request timed out");
+            return;
+        }
+    };
+
+    let request_line = request.split(|&byte| byte == b'\r').next().unwrap_or(&[]);
+    debug!("Metrics request: {}", String::from_utf8_lossy(request_line));
+    let response = if request_line.starts_with(b"GET /metrics ") {
+        let body = render_metrics(&global_state);
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    match tokio::time::timeout(REQUEST_TIMEOUT, stream.write_all(response.as_bytes())).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => debug!("Failed to write metrics response: {}", e),
+        Err(_) => debug!("Metrics response timed out"),
+    }
+}
+
+/// Reads up to the blank line ending the request head, or `None` if the
+/// connection closes or the head grows past [`MAX_REQUEST_SIZE`] first.
+async fn read_request_head(stream: &mut TcpStream) -> Option<Vec<u8>> {
+    let mut request = Vec::with_capacity(1024);
+    let mut chunk = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return None,
+            Ok(read) => request.extend_from_slice(&chunk[..read]),
+        }
+        if request.len() > MAX_REQUEST_SIZE {
+            return None;
+        }
+    }
+    Some(request)
+}
+
+/// Renders the current client and memory metrics in OpenMetrics text format.
+pub fn render_metrics(global_state: &RwLock<CompositorGlobalState>) -> String {
+    let mut clients = match global_state.read() {
+        Ok(global_state) => global_state
+            .clients
+            .iter()
+            .map(|(id, usage)| usage.snapshot(*id))
+            .collect::<Vec<_>>(),
+        Err(_) => Vec::new(),
+    };
+    clients.sort_by_key(|client| client.client_id);
+
+    let mut out = String::new();
+    let _ = writeln!(out, "# TYPE way_too_far_clients gauge");
+    let _ = writeln!(out, "# HELP way_too_far_clients Connected clients.");
+    let _ = writeln!(out, "way_too_far_clients {}", clients.len());
+
+    if let Some(resident_bytes) = resident_memory_bytes() {
+        let _ = writeln!(out, "# TYPE way_too_far_resident_memory_bytes gauge");
+        let _ = writeln!(
+            out,
+            "# HELP way_too_far_resident_memory_bytes Resident memory of the compositor."
+        );
+        let _ = writeln!(out, "way_too_far_resident_memory_bytes {}", resident_bytes);
+    }
+
+    write_client_family(
+        &mut out,
+        &clients,
+        "way_too_far_client_messages",
+        "counter",
+        "Requests received from a client.",
+        |client| client.messages,
+    );
+    write_client_family(
+        &mut out,
+        &clients,
+        "way_too_far_client_shm_bytes",
+        "gauge",
+        "Bytes of shm pools mapped for a client.",
+        |client| client.shm_bytes as u64,
+    );
+    write_client_family(
+        &mut out,
+        &clients,
+        "way_too_far_client_buffers",
+        "gauge",
+        "Buffers alive for a client.",
+        |client| client.buffers as u64,
+    );
+    write_client_family(
+        &mut out,
+        &clients,
+        "way_too_far_client_objects",
+        "gauge",
+        "Protocol objects alive for a client.",
+        |client| client.objects as u64,
+    );
+    out.push_str("# EOF\n");
+    out
+}
+
+/// Writes one metric family with a sample per client.
+fn write_client_family(
+    out: &mut String,
+    clients: &[UsageSnapshot],
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl Fn(&UsageSnapshot) -> u64,
+) {
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    // OpenMetrics counter samples carry a `_total` suffix on the family name
+    let suffix = if kind == "counter" { "_total" } else { "" };
+    for client in clients {
+        let _ = writeln!(
+            out,
+            "{}{}{{client_id=\"{}\",pid=\"{}\"}} {}",
+            name,
+            suffix,
+            client.client_id,
+            client.pid.map(|pid| pid.to_string()).unwrap_or_default(),
+            value(client)
+        );
+    }
+}
+
+/// Resident set size from `/proc/self/statm`, in bytes.
+fn resident_memory_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let resident_pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(resident_pages * page_size.max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn connect(global_state: Arc<RwLock<CompositorGlobalState>>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve_metrics(listener, global_state));
+        TcpStream::connect(address).await.unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unfinished_request_is_dropped_after_the_timeout() {
+        let global_state = Arc::new(RwLock::new(CompositorGlobalState::default()));
+        let mut stream = connect(global_state).await;
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+    }
+
+    #[tokio::test]
+    async fn oversized_request_is_dropped() {
+        let global_state = Arc::new(RwLock::new(CompositorGlobalState::default()));
+        let mut stream = connect(global_state).await;
+        let header = format!("X-Padding: {}\r\n", "a".repeat(MAX_REQUEST_SIZE));
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\n")
+            .await
+            .unwrap();
+        // The server may close before reading all of it
+        let _ = stream.write_all(header.as_bytes()).await;
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        assert!(response.is_empty());
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served() {
+        let global_state = Arc::new(RwLock::new(CompositorGlobalState::default()));
+        let mut stream = connect(global_state).await;
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    }
+}
@@ -0,0 +1,50 @@
+use crate::config::SessionConfig;
+use std::path::Path;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+/// The variables a process needs to find this compositor, as `NAME=value`.
+pub fn client_environment(config: &SessionConfig, socket_path: &Path) -> Vec<String> {
+    vec![
+        format!("WAYLAND_DISPLAY={}", socket_path.display()),
+        format!("XDG_CURRENT_DESKTOP={}", config.desktop_name),
+        "XDG_SESSION_TYPE=wayland".to_string(),
+    ]
+}
+
+/// Publishes [`client_environment`] to the systemd user manager and the D-Bus
+/// activation environment, so services they start (portals, PipeWire,
+/// activated apps) connect to this compositor.
+///
+/// Either may be missing outside a full desktop session; that is logged and
+/// otherwise ignored.
+pub async fn export_environment(config: &SessionConfig, socket_path: &Path) {
+    let environment = client_environment(config, socket_path);
+    run_quietly(
+        Command::new("systemctl")
+            .args(["--user", "set-environment"])
+            .args(&environment),
+    )
+    .await;
+    run_quietly(Command::new("dbus-update-activation-environment").args(&environment)).await;
+}
+
+async fn run_quietly(command: &mut Command) {
+    let program = command
+        .as_std()
+        .get_program()
+        .to_string_lossy()
+        .into_owned();
+    match command.output().await {
+        Ok(output) if output.status.success() => {
+            debug!("Exported session environment with {}", program)
+        }
+        Ok(output) => warn!(
+            "{} failed with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(e) => warn!("Failed to run {}: {}", program, e),
+    }
+}
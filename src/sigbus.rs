@@ -0,0 +1,109 @@
+use std::{
+    cell::Cell,
+    sync::{
+        OnceLock,
+        atomic::{Ordering, compiler_fence},
+    },
+};
+
+/// The client mapping the current thread is reading, and whether it faulted.
+#[derive(Clone, Copy)]
+struct GuardedAccess {
+    start: usize,
+    len: usize,
+    faulted: bool,
+}
+
+thread_local! {
+    static CURRENT_ACCESS: Cell<Option<GuardedAccess>> = const { Cell::new(None) };
+}
+
+static PREVIOUS_ACTION: OnceLock<libc::sigaction> = OnceLock::new();
+
+/// Installs the process-wide SIGBUS handler used by [`guarded_access`].
+///
+/// A client can truncate the file behind an shm pool at any time, which turns
+/// compositor reads of the mapping into SIGBUS. The handler replaces the
+/// faulting mapping with anonymous zero pages so the read completes, and flags
+/// the access so the caller can disconnect the client.
+pub fn install_sigbus_handler() -> anyhow::Result<()> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_sigbus as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_NODEFER;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        let mut previous: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(libc::SIGBUS, &action, &mut previous) != 0 {
+            anyhow::bail!(
+                "Failed to install SIGBUS handler: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        let _ = PREVIOUS_ACTION.set(previous);
+    }
+    Ok(())
+}
+
+/// Runs `f` over `mapping`, returning an error if the mapping faulted meanwhile.
+///
+/// After a fault the mapping reads as zeroes; the client must not be trusted
+/// with it again.
+pub fn guarded_access<R>(mapping: &[u8], f: impl FnOnce(&[u8]) -> R) -> anyhow::Result<R> {
+    CURRENT_ACCESS.with(|access| {
+        access.set(Some(GuardedAccess {
+            start: mapping.as_ptr() as usize,
+            len: mapping.len(),
+            faulted: false,
+        }))
+    });
+    // The handler updates CURRENT_ACCESS behind the compiler's back
+    compiler_fence(Ordering::SeqCst);
+    let result = f(mapping);
+    compiler_fence(Ordering::SeqCst);
+    let access = CURRENT_ACCESS.with(|access| access.take());
+
+    if access.is_some_and(|access| access.faulted) {
+        anyhow::bail!("Client shm mapping was truncated while being accessed");
+    }
+    Ok(result)
+}
+
+extern "C" fn handle_sigbus(
+    signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    _context: *mut libc::c_void,
+) {
+    let address = unsafe { (*info).si_addr() } as usize;
+    let handled = CURRENT_ACCESS.with(|current| match current.get() {
+        Some(mut access) if address >= access.start && address < access.start + access.len => {
+            let replaced = unsafe {
+                libc::mmap(
+                    access.start as *mut libc::c_void,
+                    access.len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_FIXED | libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                    -1,
+                    0,
+                )
+            };
+            if replaced == libc::MAP_FAILED {
+                return false;
+            }
+            access.faulted = true;
+            current.set(Some(access));
+            true
+        }
+        _ => false,
+    });
+
+    if !handled {
+        // Not ours: restore the previous disposition and let the fault recur
+        unsafe {
+            match PREVIOUS_ACTION.get() {
+                Some(previous) => libc::sigaction(signal, previous, std::ptr::null_mut()),
+                None => libc::signal(signal, libc::SIG_DFL) as libc::c_int,
+            };
+        }
+    }
+}
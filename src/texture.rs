@@ -0,0 +1,199 @@
+#![allow(dead_code)]
+
+use crate::{convert, wl_buffer::BufferState, wl_shm::WlShmFormat};
+
+/// Client buffer contents converted to the compositor's native ARGB8888 layout.
+///
+/// The texture lives on the surface between commits so that only the rows a
+/// client reports as damaged need to be converted again.
+#[derive(Default)]
+pub struct Texture {
+    pub width: i32,
+    pub height: i32,
+    pub pixels: Vec<u32>,
+}
+
+impl Texture {
+    /// Converts the damaged rectangles of `buffer` into the texture.
+    ///
+    /// `damage` is in buffer coordinates. If the buffer dimensions changed since
+    /// the last upload, the whole buffer is converted regardless of damage.
+    pub fn upload(
+        &mut self,
+        buffer: &BufferState,
+        pool: &[u8],
+        damage: &[(i32, i32, i32, i32)],
+    ) -> anyhow::Result<()> {
+        if buffer.width <= 0 || buffer.height <= 0 || buffer.stride <= 0 || buffer.offset < 0 {
+            anyhow::bail!(
+                "Invalid buffer geometry {}x{} stride {} offset {}",
+                buffer.width,
+                buffer.height,
+                buffer.stride,
+                buffer.offset
+            );
+        }
+        let bytes_per_pixel = WlShmFormat::bytes_per_pixel(buffer.format)
+            .ok_or_else(|| anyhow::anyhow!("Unsupported shm format {:#x}", buffer.format))?;
+        let end = buffer.offset as usize + buffer.stride as usize * buffer.height as usize;
+        if (buffer.width as usize * bytes_per_pixel) > buffer.stride as usize || end > pool.len() {
+            anyhow::bail!(
+                "Buffer of {}x{} stride {} offset {} does not fit pool of {} bytes",
+                buffer.width,
+                buffer.height,
+                buffer.stride,
+                buffer.offset,
+                pool.len()
+            );
+        }
+
+        let full = (0, 0, buffer.width, buffer.height);
+        let damage = if self.width != buffer.width || self.height != buffer.height {
+            self.width = buffer.width;
+            self.height = buffer.height;
+            self.pixels = vec![0; buffer.width as usize * buffer.height as usize];
+            std::slice::from_ref(&full)
+        } else {
+            damage
+        };
+
+        for &(x, y, width, height) in damage {
+            let Some((x0, y0, x1, y1)) =
+                clamp_rect(x, y, width, height, buffer.width, buffer.height)
+            else {
+                continue;
+            };
+            for row in y0..y1 {
+                let src_start =
+                    buffer.offset as usize + row * buffer.stride as usize + x0 * bytes_per_pixel;
+                let src = &pool[src_start..src_start + (x1 - x0) * bytes_per_pixel];
+                let dst_start = row * self.width as usize;
+                let dst = &mut self.pixels[dst_start + x0..dst_start + x1];
+                convert_row(buffer.format, src, dst);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Clamps a damage rectangle to the buffer bounds, returning `(x0, y0, x1, y1)`.
+///
+/// Clients commonly damage with `i32::MAX` extents, so the arithmetic saturates.
+fn clamp_rect(
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+    max_width: i32,
+    max_height: i32,
+) -> Option<(usize, usize, usize, usize)> {
+    let x0 = x.clamp(0, max_width);
+    let y0 = y.clamp(0, max_height);
+    let x1 = x.saturating_add(width).clamp(0, max_width);
+    let y1 = y.saturating_add(height).clamp(0, max_height);
+    if x0 >= x1 || y0 >= y1 {
+        return None;
+    }
+    Some((x0 as usize, y0 as usize, x1 as usize, y1 as usize))
+}
+
+fn convert_row(format: u32, src: &[u8], dst: &mut [u32]) {
+    match format {
+        f if f == WlShmFormat::Rgb888 as u32 => convert::rgb888_to_argb(src, dst),
+        f if f == WlShmFormat::Xrgb8888 as u32 => convert::xrgb8888_to_argb(src, dst),
+        _ => convert::argb8888_to_argb(src, dst),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{accounting::ClientUsage, wl_shm_pool::ShmPool};
+    use futures::lock::Mutex;
+    use std::sync::Arc;
+
+    const POOL_SIZE: usize = 4096;
+
+    /// An ARGB8888 buffer at the start of a pool, so pixels convert unchanged.
+    fn buffer(width: i32, height: i32) -> BufferState {
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(POOL_SIZE as u64).unwrap();
+        let usage = Arc::new(ClientUsage::default());
+        usage.add_buffer();
+        let pool = ShmPool::new(file.into(), POOL_SIZE, usage.clone()).unwrap();
+        BufferState {
+            offset: 0,
+            width,
+            height,
+            stride: width * 4,
+            format: WlShmFormat::Argb8888 as u32,
+            shm_pool: Arc::new(Mutex::new(pool)),
+            usage,
+        }
+    }
+
+    /// Pool bytes with every pixel set to `pixel`.
+    fn pool_of(pixel: u32) -> Vec<u8> {
+        pixel.to_le_bytes().repeat(POOL_SIZE / 4)
+    }
+
+    /// Uploads a 4x4 buffer of `1` pixels, then only `damage` of it as `2`s.
+    fn damaged(damage: (i32, i32, i32, i32)) -> Texture {
+        let buffer = buffer(4, 4);
+        let mut texture = Texture::default();
+        texture.upload(&buffer, &pool_of(1), &[]).unwrap();
+        texture.upload(&buffer, &pool_of(2), &[damage]).unwrap();
+        texture
+    }
+
+    fn rows(texture: &Texture) -> Vec<Vec<u32>> {
+        texture
+            .pixels
+            .chunks(texture.width as usize)
+            .map(<[u32]>::to_vec)
+            .collect()
+    }
+
+    #[test]
+    fn damage_past_the_buffer_is_clamped() {
+        let texture = damaged((2, 3, 100, i32::MAX));
+        assert_eq!(
+            rows(&texture),
+            [[1, 1, 1, 1], [1, 1, 1, 1], [1, 1, 1, 1], [1, 1, 2, 2]]
+        );
+    }
+
+    #[test]
+    fn negative_damage_is_clamped_to_the_origin() {
+        let texture = damaged((-3, -2, 4, 3));
+        assert_eq!(
+            rows(&texture),
+            [[2, 1, 1, 1], [1, 1, 1, 1], [1, 1, 1, 1], [1, 1, 1, 1]]
+        );
+    }
+
+    #[test]
+    fn damage_outside_the_buffer_converts_nothing() {
+        for damage in [(-8, 0, 4, 4), (0, 4, 4, 4), (1, 1, 0, 2), (1, 1, -2, 2)] {
+            assert_eq!(damaged(damage).pixels, [1; 16], "damage {:?}", damage);
+        }
+    }
+
+    #[test]
+    fn resized_buffer_is_converted_whole() {
+        let mut texture = Texture::default();
+        texture.upload(&buffer(4, 4), &pool_of(1), &[]).unwrap();
+        texture
+            .upload(&buffer(8, 2), &pool_of(2), &[(0, 0, 1, 1)])
+            .unwrap();
+        assert_eq!((texture.width, texture.height), (8, 2));
+        assert_eq!(texture.pixels, [2; 16]);
+    }
+
+    #[test]
+    fn buffer_past_the_pool_is_rejected() {
+        let mut texture = Texture::default();
+        let pool = pool_of(1);
+        assert!(texture.upload(&buffer(4, 4), &pool[..63], &[]).is_err());
+    }
+}
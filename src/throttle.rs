@@ -0,0 +1,83 @@
+use crate::config::LimitsConfig;
+use std::time::{Duration, Instant};
+
+/// Score added each time a client exhausts its request burst.
+const RATE_VIOLATION_SCORE: u32 = 5;
+/// Score added for requests a well-behaved client would never send.
+const BAD_REQUEST_SCORE: u32 = 1;
+/// The misbehavior score is halved once per interval without new offences.
+const SCORE_DECAY_INTERVAL: Duration = Duration::from_secs(10);
+
+pub enum ThrottleDecision {
+    Allow,
+    /// Stop reading from the client for this long before dispatching again.
+    Pause(Duration),
+    Disconnect,
+}
+
+/// Token-bucket rate limiting plus a decaying misbehavior score for one client.
+pub struct Throttle {
+    limits: LimitsConfig,
+    tokens: f64,
+    last_refill: Instant,
+    score: u32,
+    last_decay: Instant,
+}
+
+impl Throttle {
+    pub fn new(limits: LimitsConfig) -> Self {
+        let now = Instant::now();
+        Throttle {
+            tokens: limits.burst as f64,
+            limits,
+            last_refill: now,
+            score: 0,
+            last_decay: now,
+        }
+    }
+
+    pub fn score(&self) -> u32 {
+        self.score
+    }
+
+    /// Accounts for one incoming request.
+    pub fn check_request(&mut self, now: Instant) -> ThrottleDecision {
+        self.decay(now);
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.limits.requests_per_second as f64)
+            .min(self.limits.burst as f64);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return ThrottleDecision::Allow;
+        }
+        self.add_score(RATE_VIOLATION_SCORE, now);
+        if self.score >= self.limits.max_misbehavior_score {
+            ThrottleDecision::Disconnect
+        } else {
+            ThrottleDecision::Pause(Duration::from_millis(self.limits.pause_ms))
+        }
+    }
+
+    /// Records a request the compositor had to ignore, such as one for an unknown object.
+    ///
+    /// Returns whether the client should now be disconnected.
+    pub fn record_bad_request(&mut self, now: Instant) -> bool {
+        self.decay(now);
+        self.add_score(BAD_REQUEST_SCORE, now);
+        self.score >= self.limits.max_misbehavior_score
+    }
+
+    fn add_score(&mut self, amount: u32, now: Instant) {
+        self.score = self.score.saturating_add(amount);
+        self.last_decay = now;
+    }
+
+    fn decay(&mut self, now: Instant) {
+        while self.score > 0 && now.duration_since(self.last_decay) >= SCORE_DECAY_INTERVAL {
+            self.score /= 2;
+            self.last_decay += SCORE_DECAY_INTERVAL;
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+/// Size of the object id, opcode and length header preceding every message.
+pub const HEADER_SIZE: usize = 8;
+
+/// A single request split out of the client byte stream.
+///
+/// `args` shares the read buffer's allocation, so framing never copies
+/// argument bytes.
+pub struct Message {
+    pub object_id: u32,
+    pub op_code: u16,
+    pub args: Bytes,
+}
+
+/// Splits the next complete message off the front of `buffer`.
+///
+/// Returns `Ok(None)` when more bytes are needed, and an error when the
+/// header announces a length shorter than the header itself.
+pub fn decode_message(buffer: &mut BytesMut) -> anyhow::Result<Option<Message>> {
+    if buffer.len() < HEADER_SIZE {
+        return Ok(None);
+    }
+    let message_length = u16::from_le_bytes([buffer[6], buffer[7]]) as usize;
+    if message_length < HEADER_SIZE {
+        anyhow::bail!(
+            "Message length {} is shorter than its header",
+            message_length
+        );
+    }
+    if buffer.len() < message_length {
+        return Ok(None);
+    }
+
+    let mut frame = buffer.split_to(message_length).freeze();
+    let object_id = frame.get_u32_le();
+    let op_code = frame.get_u16_le();
+    frame.advance(2);
+    Ok(Some(Message {
+        object_id,
+        op_code,
+        args: frame,
+    }))
+}
+
+/// A request whose arguments don't fit the message or aren't well formed.
+#[derive(Debug)]
+pub enum ArgumentError {
+    Truncated,
+    InvalidString,
+    MissingFd,
+}
+
+impl std::fmt::Display for ArgumentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArgumentError::Truncated => write!(f, "request arguments are truncated"),
+            ArgumentError::InvalidString => write!(f, "request string argument is malformed"),
+            ArgumentError::MissingFd => write!(f, "request is missing a file descriptor"),
+        }
+    }
+}
+
+impl std::error::Error for ArgumentError {}
+
+/// Reads a request's arguments in order.
+///
+/// Every read is bounds-checked, so a message too short for its signature is
+/// an [`ArgumentError`] rather than a panic.
+pub struct ArgReader<'a> {
+    args: &'a [u8],
+}
+
+impl<'a> ArgReader<'a> {
+    pub fn new(args: &'a [u8]) -> Self {
+        ArgReader { args }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], ArgumentError> {
+        if self.args.len() < length {
+            return Err(ArgumentError::Truncated);
+        }
+        let (taken, rest) = self.args.split_at(length);
+        self.args = rest;
+        Ok(taken)
+    }
+
+    /// A uint, object or new_id argument.
+    pub fn uint(&mut self) -> Result<u32, ArgumentError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn int(&mut self) -> Result<i32, ArgumentError> {
+        Ok(self.uint()? as i32)
+    }
+
+    /// A non-null, NUL-terminated UTF-8 string padded to 32 bits.
+    pub fn string(&mut self) -> Result<&'a str, ArgumentError> {
+        let length = self.uint()? as usize;
+        let bytes = self.take(
+            length
+                .checked_add(padding(length))
+                .ok_or(ArgumentError::Truncated)?,
+        )?;
+        match bytes[..length].split_last() {
+            Some((0, value)) => {
+                std::str::from_utf8(value).map_err(|_| ArgumentError::InvalidString)
+            }
+            _ => Err(ArgumentError::InvalidString),
+        }
+    }
+}
+
+/// Appends an event with the given arguments to `buffer`.
+///
+/// Fails, leaving `buffer` untouched, when the event is too long for the
+/// header's 16-bit length field.
+pub fn encode_message(
+    buffer: &mut BytesMut,
+    object_id: u32,
+    op_code: u16,
+    args: &[u8],
+) -> anyhow::Result<()> {
+    let Ok(length) = u16::try_from(HEADER_SIZE + args.len()) else {
+        anyhow::bail!(
+            "Event of {} bytes is too long for the wire format",
+            HEADER_SIZE + args.len()
+        );
+    };
+    buffer.reserve(length as usize);
+    buffer.put_u32_le(object_id);
+    buffer.put_u16_le(op_code);
+    buffer.put_u16_le(length);
+    buffer.put_slice(args);
+    Ok(())
+}
+
+/// Encodes one event directly into an outgoing buffer, with no intermediate
+/// argument allocation.
+///
+/// The header is written up front and its length is filled in when the
+/// writer is dropped, so an event is complete as soon as the writer goes out
+/// of scope. An event too long for the 16-bit length field is a compositor
+/// bug: it trips a debug assertion, and release builds drop the event rather
+/// than send a corrupt frame.
+pub struct EventWriter<'a> {
+    buffer: &'a mut BytesMut,
+    start: usize,
+}
+
+impl<'a> EventWriter<'a> {
+    pub fn new(buffer: &'a mut BytesMut, object_id: u32, op_code: u16) -> Self {
+        let start = buffer.len();
+        buffer.put_u32_le(object_id);
+        buffer.put_u16_le(op_code);
+        buffer.put_u16_le(0);
+        EventWriter { buffer, start }
+    }
+
+    pub fn uint(&mut self, value: u32) -> &mut Self {
+        self.buffer.put_u32_le(value);
+        self
+    }
+
+    pub fn int(&mut self, value: i32) -> &mut Self {
+        self.buffer.put_i32_le(value);
+        self
+    }
+
+    /// A 24.8 signed fixed-point number.
+    pub fn fixed(&mut self, value: f64) -> &mut Self {
+        self.buffer.put_i32_le((value * 256.0) as i32);
+        self
+    }
+
+    /// A length-prefixed, NUL-terminated string padded to 32 bits.
+    pub fn string(&mut self, value: &str) -> &mut Self {
+        let length = value.len() + 1;
+        self.buffer.put_u32_le(length as u32);
+        self.buffer.put_slice(value.as_bytes());
+        self.buffer.put_bytes(0, 1 + padding(length));
+        self
+    }
+
+    /// A length-prefixed byte array padded to 32 bits.
+    pub fn array(&mut self, value: &[u8]) -> &mut Self {
+        self.buffer.put_u32_le(value.len() as u32);
+        self.buffer.put_slice(value);
+        self.buffer.put_bytes(0, padding(value.len()));
+        self
+    }
+}
+
+impl Drop for EventWriter<'_> {
+    fn drop(&mut self) {
+        let length = self.buffer.len() - self.start;
+        let Ok(length) = u16::try_from(length) else {
+            debug_assert!(false, "event of {} bytes overflows its header", length);
+            tracing::error!("Dropping event of {} bytes, too long to send", length);
+            self.buffer.truncate(self.start);
+            return;
+        };
+        self.buffer[self.start + 6..self.start + 8].copy_from_slice(&length.to_le_bytes());
+    }
+}
+
+/// Bytes needed to pad `length` up to a multiple of four.
+fn padding(length: usize) -> usize {
+    (4 - length % 4) % 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_length_is_written_to_the_header() {
+        let mut buffer = BytesMut::new();
+        EventWriter::new(&mut buffer, 3, 1).uint(7).string("seat0");
+        let message = decode_message(&mut buffer).unwrap().unwrap();
+        assert_eq!((message.object_id, message.op_code), (3, 1));
+        assert_eq!(message.args.len(), 4 + 4 + 8);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn oversized_message_is_rejected_untouched() {
+        let mut buffer = BytesMut::new();
+        let args = vec![0; u16::MAX as usize];
+        assert!(encode_message(&mut buffer, 3, 0, &args).is_err());
+        assert!(buffer.is_empty());
+        encode_message(&mut buffer, 3, 0, &args[..u16::MAX as usize - HEADER_SIZE]).unwrap();
+        assert_eq!(buffer.len(), u16::MAX as usize);
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "overflows its header"))]
+    fn oversized_event_is_never_sent_corrupt() {
+        let mut buffer = BytesMut::new();
+        EventWriter::new(&mut buffer, 3, 0).array(&[0; u16::MAX as usize]);
+        assert!(buffer.is_empty());
+    }
+}
@@ -1,10 +1,9 @@
 #![allow(dead_code)]
 
-use crate::CompositorClientState;
+use crate::{CompositorClientState, WaylandObject, accounting::ClientUsage, wl_shm_pool::ShmPool};
 use futures::lock::Mutex;
-use memmap2::MmapMut;
 use std::sync::Arc;
-use tracing::{debug, warn};
+use tracing::debug;
 
 pub struct BufferState {
     pub offset: i32,
@@ -12,7 +11,14 @@ pub struct BufferState {
     pub height: i32,
     pub stride: i32,
     pub format: u32,
-    pub shm_pool: Arc<Mutex<MmapMut>>,
+    pub shm_pool: Arc<Mutex<ShmPool>>,
+    pub usage: Arc<ClientUsage>,
+}
+
+impl Drop for BufferState {
+    fn drop(&mut self) {
+        self.usage.remove_buffer();
+    }
 }
 
 impl<'a> CompositorClientState<'a> {
@@ -25,9 +31,7 @@ impl<'a> CompositorClientState<'a> {
             0 => {
                 self.handle_wl_buffer_destroy(object_id).await?;
             }
-            _ => {
-                warn!("Unknown op_code {} for wl_buffer", op_code);
-            }
+            _ => self.invalid_method(object_id, op_code).await?,
         }
         Ok(())
     }
@@ -35,6 +39,11 @@ impl<'a> CompositorClientState<'a> {
     pub async fn handle_wl_buffer_destroy(&mut self, object_id: u32) -> anyhow::Result<()> {
         debug!("Buffer.destroy called for id {}", object_id);
         self.object_registry.remove(&object_id);
+        for object in self.object_registry.values_mut() {
+            if let WaylandObject::WlSurface(surface) = object {
+                surface.forget_buffer(object_id);
+            }
+        }
         Ok(())
     }
 
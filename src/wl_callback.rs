@@ -1,12 +1,15 @@
 #![allow(dead_code)]
 
-use crate::CompositorClientState;
-use tracing::{debug, warn};
+use crate::{CompositorClientState, logging::debug_sampled};
 
 impl<'a> CompositorClientState<'a> {
-    pub async fn handle_wl_callback_message(&mut self, op_code: u16) -> anyhow::Result<()> {
-        warn!("Unknown op_code {} for wl_callback", op_code);
-        Ok(())
+    pub async fn handle_wl_callback_message(
+        &mut self,
+        object_id: u32,
+        op_code: u16,
+    ) -> anyhow::Result<()> {
+        // wl_callback has no requests
+        self.invalid_method(object_id, op_code).await
     }
 
     pub async fn send_callback_done(
@@ -15,7 +18,11 @@ impl<'a> CompositorClientState<'a> {
         callback_data: u32,
     ) -> anyhow::Result<()> {
         let argument_bytes = callback_data.to_le_bytes();
-        debug!("Sending callback done event for id {}", callback_id);
-        self.send_message(callback_id, 0, &argument_bytes).await
+        debug_sampled!("Sending callback done event for id {}", callback_id);
+        self.send_message(callback_id, 0, &argument_bytes).await?;
+
+        // done is a destructor event, so the callback is gone once it is sent
+        self.object_registry.remove(&callback_id);
+        self.send_wl_display_delete_id(callback_id).await
     }
 }
@@ -1,42 +1,54 @@
 #![allow(dead_code)]
 
-use crate::{CompositorClientState, WaylandObject, wl_surface::SurfaceState};
-use tracing::{debug, warn};
+use crate::{CompositorClientState, WaylandObject, wire::ArgReader, wl_surface::SurfaceState};
+use tracing::debug;
 
 impl<'a> CompositorClientState<'a> {
     pub async fn handle_wl_compositor_message(
         &mut self,
+        object_id: u32,
         op_code: u16,
         arg_bytes: &[u8],
     ) -> anyhow::Result<()> {
         match op_code {
-            0 => self.handle_wl_compositor_create_surface(arg_bytes).await?,
-            1 => self.handle_wl_compositor_create_region(arg_bytes).await?,
-            _ => {
-                warn!("Unknown op_code {} for wl_compositor", op_code);
+            0 => {
+                self.handle_wl_compositor_create_surface(object_id, arg_bytes)
+                    .await?
             }
+            1 => {
+                self.handle_wl_compositor_create_region(object_id, arg_bytes)
+                    .await?
+            }
+            _ => self.invalid_method(object_id, op_code).await?,
         }
         Ok(())
     }
 
     pub async fn handle_wl_compositor_create_surface(
         &mut self,
+        object_id: u32,
         arg_bytes: &[u8],
     ) -> anyhow::Result<()> {
-        let new_id = u32::from_le_bytes(arg_bytes[..4].try_into().unwrap());
+        let mut args = ArgReader::new(arg_bytes);
+        let new_id = args.uint()?;
         debug!("WlCompositor.create_surface called with new_id {}", new_id);
-        self.object_registry
-            .insert(new_id, WaylandObject::WlSurface(SurfaceState::default()));
-        Ok(())
+        self.insert_new_object(
+            object_id,
+            new_id,
+            WaylandObject::WlSurface(SurfaceState::default()),
+        )
+        .await
     }
 
     pub async fn handle_wl_compositor_create_region(
         &mut self,
+        object_id: u32,
         arg_bytes: &[u8],
     ) -> anyhow::Result<()> {
-        let new_id = u32::from_le_bytes(arg_bytes[..4].try_into().unwrap());
+        let mut args = ArgReader::new(arg_bytes);
+        let new_id = args.uint()?;
         debug!("WlCompositor.create_region called with new_id {}", new_id);
-        self.object_registry.insert(new_id, WaylandObject::WlRegion);
-        Ok(())
+        self.insert_new_object(object_id, new_id, WaylandObject::WlRegion)
+            .await
     }
 }
@@ -1,70 +1,78 @@
 #![allow(dead_code)]
 
-use crate::{
-    CompositorClientState, CompositorGlobalState, WaylandObject, utils::get_wayland_string_bytes,
-};
-use futures::lock::MutexGuard;
-use tracing::{debug, warn};
+use crate::{CompositorClientState, WaylandObject, wire::ArgReader};
+use tracing::{debug, info};
+
+#[derive(Clone, Copy)]
+#[repr(u32)]
+pub enum WlDisplayError {
+    InvalidObject = 0,
+    InvalidMethod = 1,
+    NoMemory = 2,
+    Implementation = 3,
+}
 
 impl<'a> CompositorClientState<'a> {
     pub async fn handle_wl_display_message(
         &mut self,
+        object_id: u32,
         op_code: u16,
         arg_bytes: &[u8],
-        global_state: MutexGuard<'_, CompositorGlobalState>,
     ) -> anyhow::Result<()> {
         match op_code {
             0 => self.handle_wl_display_sync(arg_bytes).await?,
-            1 => {
-                self.handle_wl_display_get_registry(arg_bytes, global_state)
-                    .await?
-            }
-            _ => {
-                warn!("Unknown op_code {} for wl_display", op_code);
-            }
+            1 => self.handle_wl_display_get_registry(arg_bytes).await?,
+            _ => self.invalid_method(object_id, op_code).await?,
         }
         Ok(())
     }
 
     pub async fn handle_wl_display_sync(&mut self, arg_bytes: &[u8]) -> anyhow::Result<()> {
-        let new_id = u32::from_le_bytes(arg_bytes[..4].try_into().unwrap());
+        let mut args = ArgReader::new(arg_bytes);
+        let new_id = args.uint()?;
         debug!("Display sync called with new_id {}", new_id);
 
-        self.object_registry
-            .insert(new_id, WaylandObject::WlCallback);
+        self.insert_new_object(1, new_id, WaylandObject::WlCallback)
+            .await?;
         self.send_callback_done(new_id, 0).await?;
         Ok(())
     }
 
-    pub async fn handle_wl_display_get_registry(
-        &mut self,
-        arg_bytes: &[u8],
-        global_state: MutexGuard<'_, CompositorGlobalState>,
-    ) -> anyhow::Result<()> {
-        let new_id = u32::from_le_bytes(arg_bytes[..4].try_into().unwrap());
+    pub async fn handle_wl_display_get_registry(&mut self, arg_bytes: &[u8]) -> anyhow::Result<()> {
+        let mut args = ArgReader::new(arg_bytes);
+        let new_id = args.uint()?;
         debug!("Display get_registry called with new_id {}", new_id);
-        self.object_registry
-            .insert(new_id, WaylandObject::WlRegistry);
+        self.insert_new_object(1, new_id, WaylandObject::WlRegistry)
+            .await?;
 
-        for (name, interface, version) in &global_state.globals {
+        let globals = self.globals.load_full();
+        for (name, interface, version) in globals.iter() {
+            if !self.global_allowed(*interface) {
+                info!(
+                    "Policy hides {} from client {} ({:?})",
+                    interface.as_str(),
+                    self.client_id,
+                    self.executable
+                );
+                continue;
+            }
             self.send_global(new_id, *name, interface.as_str(), *version)
                 .await?;
         }
         Ok(())
     }
 
-    pub async fn send_wl_display_error(&mut self, code: u32, message: &str) -> anyhow::Result<()> {
-        let mut args = Vec::new();
-        args.extend_from_slice(&code.to_le_bytes());
-        args.extend_from_slice(&get_wayland_string_bytes(message));
-
-        self.send_message(1, 0, &args).await
+    pub async fn send_wl_display_error(
+        &mut self,
+        object_id: u32,
+        code: u32,
+        message: &str,
+    ) -> anyhow::Result<()> {
+        self.event(1, 0).uint(object_id).uint(code).string(message);
+        self.flush_if_full().await
     }
 
     pub async fn send_wl_display_delete_id(&mut self, id: u32) -> anyhow::Result<()> {
-        let mut args = Vec::new();
-        args.extend_from_slice(&id.to_le_bytes());
-
-        self.send_message(1, 1, &args).await
+        self.send_message(1, 1, &id.to_le_bytes()).await
     }
 }
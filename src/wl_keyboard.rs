@@ -0,0 +1,268 @@
+#![allow(dead_code)]
+
+use crate::{CompositorClientState, WaylandObject, input::KeyboardEvent, xkb::Modifiers};
+use std::{
+    fs::File,
+    io::Write,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+use tracing::debug;
+
+#[derive(Clone, Copy)]
+#[repr(u32)]
+pub enum WlKeyboardKeymapFormat {
+    NoKeymap = 0,
+    XkbV1 = 1,
+}
+
+#[derive(Clone, Copy)]
+#[repr(u32)]
+pub enum WlKeyboardKeyState {
+    Released = 0,
+    Pressed = 1,
+}
+
+/// A keymap in the memfd handed to clients.
+///
+/// The file is sealed against writes and resizing, so every client can map
+/// the same one without being able to change it for the others.
+pub struct KeymapFile {
+    fd: OwnedFd,
+    /// Including the terminating NUL.
+    size: u32,
+}
+
+impl KeymapFile {
+    pub fn new(keymap: &str) -> anyhow::Result<KeymapFile> {
+        let fd = unsafe {
+            libc::memfd_create(
+                c"way-too-far-keymap".as_ptr(),
+                libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+            )
+        };
+        if fd < 0 {
+            anyhow::bail!(
+                "Failed to create keymap memfd: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(keymap.as_bytes())?;
+        file.write_all(&[0])?;
+        let seals =
+            libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE | libc::F_SEAL_SEAL;
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_ADD_SEALS, seals) } != 0 {
+            anyhow::bail!(
+                "Failed to seal keymap memfd: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(KeymapFile {
+            fd: file.into(),
+            size: keymap.len() as u32 + 1,
+        })
+    }
+}
+
+/// The keyboard as one client sees it.
+#[derive(Default)]
+pub struct ClientKeyboard {
+    /// The client's surface with keyboard focus.
+    focus: Option<u32>,
+    /// Keys held while the client has focus, for keyboards created later.
+    keys: Vec<u32>,
+    modifiers: Modifiers,
+    enter_serial: u32,
+}
+
+impl<'a> CompositorClientState<'a> {
+    pub async fn handle_wl_keyboard_message(
+        &mut self,
+        object_id: u32,
+        op_code: u16,
+        version: u32,
+    ) -> anyhow::Result<()> {
+        match op_code {
+            0 if version >= 3 => self.handle_wl_keyboard_release(object_id).await?,
+            _ => self.invalid_method(object_id, op_code).await?,
+        }
+        Ok(())
+    }
+
+    pub async fn handle_wl_keyboard_release(&mut self, object_id: u32) -> anyhow::Result<()> {
+        debug!("WlKeyboard.release called for id {}", object_id);
+        self.object_registry.remove(&object_id);
+        Ok(())
+    }
+
+    /// Sends the keymap and repeat info to a new keyboard, and focus if the
+    /// client already has it.
+    pub async fn send_wl_keyboard_info(
+        &mut self,
+        keyboard_id: u32,
+        version: u32,
+    ) -> anyhow::Result<()> {
+        let keyboard = self.global_state.read().ok().and_then(|global_state| {
+            global_state
+                .keyboard
+                .as_ref()
+                .map(|keyboard| (keyboard.keymap.clone(), keyboard.repeat_delay))
+        });
+        match keyboard {
+            Some((keymap, repeat_delay)) => {
+                self.attach_fd(keymap.fd.try_clone()?).await?;
+                self.event(keyboard_id, 0)
+                    .uint(WlKeyboardKeymapFormat::XkbV1 as u32)
+                    .uint(keymap.size);
+                if version >= 4 {
+                    // A rate of 0 leaves repeating to the compositor
+                    self.event(keyboard_id, 5).int(0).int(repeat_delay as i32);
+                }
+            }
+            None => {
+                // The seat lost its keyboard; the fd is required regardless
+                self.attach_fd(File::open("/dev/null")?.into()).await?;
+                self.event(keyboard_id, 0)
+                    .uint(WlKeyboardKeymapFormat::NoKeymap as u32)
+                    .uint(0);
+            }
+        }
+
+        if let Some(surface_id) = self.keyboard.focus {
+            let serial = self.keyboard.enter_serial;
+            let keys = keys_array(&self.keyboard.keys);
+            let modifiers = self.keyboard.modifiers;
+            self.event(keyboard_id, 1)
+                .uint(serial)
+                .uint(surface_id)
+                .array(&keys);
+            self.send_wl_keyboard_modifiers(keyboard_id, serial, modifiers);
+        }
+        self.flush_if_full().await
+    }
+
+    /// `(object_id, version)` of every wl_keyboard the client has.
+    fn wl_keyboards(&self) -> Vec<(u32, u32)> {
+        self.object_registry
+            .iter()
+            .filter_map(|(&id, object)| match object {
+                WaylandObject::WlKeyboard { version } => Some((id, *version)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Turns keyboard input routed to this client into events on its
+    /// keyboards.
+    pub async fn handle_keyboard_event(&mut self, event: KeyboardEvent) -> anyhow::Result<()> {
+        let keyboards = self.wl_keyboards();
+        match event {
+            KeyboardEvent::Enter {
+                surface_id,
+                keys,
+                modifiers,
+            } => {
+                // The surface may have been destroyed since focus moved
+                if !matches!(
+                    self.object_registry.get(&surface_id),
+                    Some(WaylandObject::WlSurface(_))
+                ) {
+                    return Ok(());
+                }
+                let serial = self.next_serial();
+                let keys_array = keys_array(&keys);
+                self.keyboard = ClientKeyboard {
+                    focus: Some(surface_id),
+                    keys,
+                    modifiers,
+                    enter_serial: serial,
+                };
+                for &(keyboard_id, _) in &keyboards {
+                    self.event(keyboard_id, 1)
+                        .uint(serial)
+                        .uint(surface_id)
+                        .array(&keys_array);
+                }
+                let serial = self.next_serial();
+                for (keyboard_id, _) in keyboards {
+                    self.send_wl_keyboard_modifiers(keyboard_id, serial, modifiers);
+                }
+            }
+            KeyboardEvent::Leave { surface_id } => {
+                if self.keyboard.focus != Some(surface_id) {
+                    return Ok(());
+                }
+                self.keyboard.focus = None;
+                self.keyboard.keys.clear();
+                if !self.object_registry.contains_key(&surface_id) {
+                    return Ok(());
+                }
+                let serial = self.next_serial();
+                for (keyboard_id, _) in keyboards {
+                    self.event(keyboard_id, 2).uint(serial).uint(surface_id);
+                }
+            }
+            KeyboardEvent::Key { time, key, pressed } => {
+                if self.keyboard.focus.is_none() {
+                    return Ok(());
+                }
+                let state = if pressed {
+                    self.keyboard.keys.push(key);
+                    WlKeyboardKeyState::Pressed
+                } else {
+                    self.keyboard.keys.retain(|&held| held != key);
+                    WlKeyboardKeyState::Released
+                };
+                let serial = self.next_serial();
+                for (keyboard_id, _) in keyboards {
+                    self.event(keyboard_id, 3)
+                        .uint(serial)
+                        .uint(time)
+                        .uint(key)
+                        .uint(state as u32);
+                }
+            }
+            KeyboardEvent::Repeat { time, key } => {
+                if self.keyboard.focus.is_none() {
+                    return Ok(());
+                }
+                let serial = self.next_serial();
+                for (keyboard_id, version) in keyboards {
+                    // Older keyboards were never told repeat is done for them
+                    if version >= 4 {
+                        self.event(keyboard_id, 3)
+                            .uint(serial)
+                            .uint(time)
+                            .uint(key)
+                            .uint(WlKeyboardKeyState::Pressed as u32);
+                    }
+                }
+            }
+            KeyboardEvent::Modifiers(modifiers) => {
+                if self.keyboard.focus.is_none() {
+                    return Ok(());
+                }
+                self.keyboard.modifiers = modifiers;
+                let serial = self.next_serial();
+                for (keyboard_id, _) in keyboards {
+                    self.send_wl_keyboard_modifiers(keyboard_id, serial, modifiers);
+                }
+            }
+        }
+        self.flush_if_full().await
+    }
+
+    fn send_wl_keyboard_modifiers(&mut self, keyboard_id: u32, serial: u32, modifiers: Modifiers) {
+        self.event(keyboard_id, 4)
+            .uint(serial)
+            .uint(modifiers.depressed)
+            .uint(modifiers.latched)
+            .uint(modifiers.locked)
+            .uint(modifiers.group);
+    }
+}
+
+/// Encodes held keys as the array argument of wl_keyboard.enter.
+fn keys_array(keys: &[u32]) -> Vec<u8> {
+    keys.iter().flat_map(|key| key.to_le_bytes()).collect()
+}
@@ -1,7 +1,7 @@
 #![allow(dead_code)]
 
 use crate::CompositorClientState;
-use tracing::warn;
+use tracing::debug;
 
 #[derive(Default, Clone, Copy)]
 #[repr(u32)]
@@ -17,14 +17,41 @@ pub enum WlOutputTransform {
     Flipped270 = 7,
 }
 
+impl TryFrom<i32> for WlOutputTransform {
+    type Error = i32;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => WlOutputTransform::Normal,
+            1 => WlOutputTransform::Rotate90,
+            2 => WlOutputTransform::Rotate180,
+            3 => WlOutputTransform::Rotate270,
+            4 => WlOutputTransform::Flipped,
+            5 => WlOutputTransform::Flipped90,
+            6 => WlOutputTransform::Flipped180,
+            7 => WlOutputTransform::Flipped270,
+            _ => return Err(value),
+        })
+    }
+}
+
 impl<'a> CompositorClientState<'a> {
     pub async fn handle_wl_output_message(
         &mut self,
         object_id: u32,
         op_code: u16,
-        arg_bytes: &[u8],
+        _arg_bytes: &[u8],
     ) -> anyhow::Result<()> {
-        warn!("Unknown op_code {} for wl_output", op_code);
+        match op_code {
+            0 => self.handle_wl_output_release(object_id).await?,
+            _ => self.invalid_method(object_id, op_code).await?,
+        }
+        Ok(())
+    }
+
+    pub async fn handle_wl_output_release(&mut self, object_id: u32) -> anyhow::Result<()> {
+        debug!("WlOutput.release called for id {}", object_id);
+        self.object_registry.remove(&object_id);
         Ok(())
     }
 }
@@ -0,0 +1,201 @@
+#![allow(dead_code)]
+
+use crate::{CompositorClientState, WaylandObject, input::PointerEvent, wire::ArgReader};
+use tracing::debug;
+
+#[derive(Clone, Copy)]
+#[repr(u32)]
+pub enum WlPointerButtonState {
+    Released = 0,
+    Pressed = 1,
+}
+
+/// The pointer as one client sees it.
+#[derive(Default)]
+pub struct ClientPointer {
+    /// The client's surface with pointer focus.
+    focus: Option<u32>,
+    /// Surface-local position within `focus`.
+    position: (f64, f64),
+    /// Serial of the last enter, which `set_cursor` must quote.
+    enter_serial: u32,
+}
+
+impl<'a> CompositorClientState<'a> {
+    pub async fn handle_wl_pointer_message(
+        &mut self,
+        object_id: u32,
+        op_code: u16,
+        arg_bytes: &[u8],
+        version: u32,
+    ) -> anyhow::Result<()> {
+        match op_code {
+            0 => {
+                self.handle_wl_pointer_set_cursor(object_id, arg_bytes)
+                    .await?
+            }
+            1 if version >= 3 => self.handle_wl_pointer_release(object_id).await?,
+            _ => self.invalid_method(object_id, op_code).await?,
+        }
+        Ok(())
+    }
+
+    pub async fn handle_wl_pointer_set_cursor(
+        &mut self,
+        object_id: u32,
+        arg_bytes: &[u8],
+    ) -> anyhow::Result<()> {
+        let mut args = ArgReader::new(arg_bytes);
+        let serial = args.uint()?;
+        let surface_id = args.uint()?;
+        let hotspot_x = args.int()?;
+        let hotspot_y = args.int()?;
+        self.check_object_argument(object_id, surface_id, true, |object| {
+            matches!(object, WaylandObject::WlSurface(_))
+        })
+        .await?;
+        // There is no cursor to draw yet, but keep the protocol's rule that
+        // only the client that was last entered may set it
+        if self.pointer.focus.is_none() || serial != self.pointer.enter_serial {
+            debug!("WlPointer.set_cursor with stale serial {} ignored", serial);
+            return Ok(());
+        }
+        debug!(
+            "WlPointer.set_cursor to surface {} with hotspot ({}, {})",
+            surface_id, hotspot_x, hotspot_y
+        );
+        Ok(())
+    }
+
+    pub async fn handle_wl_pointer_release(&mut self, object_id: u32) -> anyhow::Result<()> {
+        debug!("WlPointer.release called for id {}", object_id);
+        self.object_registry.remove(&object_id);
+        Ok(())
+    }
+
+    /// `(object_id, version)` of every wl_pointer the client has.
+    fn wl_pointers(&self) -> Vec<(u32, u32)> {
+        self.object_registry
+            .iter()
+            .filter_map(|(&id, object)| match object {
+                WaylandObject::WlPointer { version } => Some((id, *version)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Turns pointer input routed to this client into events on its pointers.
+    pub async fn handle_pointer_event(&mut self, event: PointerEvent) -> anyhow::Result<()> {
+        let pointers = self.wl_pointers();
+        match event {
+            PointerEvent::Enter { surface_id, x, y } => {
+                // The surface may have been destroyed since focus moved
+                if !matches!(
+                    self.object_registry.get(&surface_id),
+                    Some(WaylandObject::WlSurface(_))
+                ) {
+                    return Ok(());
+                }
+                let serial = self.next_serial();
+                self.pointer = ClientPointer {
+                    focus: Some(surface_id),
+                    position: (x, y),
+                    enter_serial: serial,
+                };
+                for (pointer_id, _) in pointers {
+                    self.event(pointer_id, 0)
+                        .uint(serial)
+                        .uint(surface_id)
+                        .fixed(x)
+                        .fixed(y);
+                }
+            }
+            PointerEvent::Leave { surface_id } => {
+                if self.pointer.focus != Some(surface_id) {
+                    return Ok(());
+                }
+                self.pointer.focus = None;
+                if !self.object_registry.contains_key(&surface_id) {
+                    return Ok(());
+                }
+                let serial = self.next_serial();
+                for (pointer_id, _) in pointers {
+                    self.event(pointer_id, 1).uint(serial).uint(surface_id);
+                }
+            }
+            PointerEvent::Motion { time, x, y } => {
+                if self.pointer.focus.is_none() {
+                    return Ok(());
+                }
+                self.pointer.position = (x, y);
+                for (pointer_id, _) in pointers {
+                    self.event(pointer_id, 2).uint(time).fixed(x).fixed(y);
+                }
+            }
+            PointerEvent::Button {
+                time,
+                button,
+                pressed,
+            } => {
+                if self.pointer.focus.is_none() {
+                    return Ok(());
+                }
+                let state = if pressed {
+                    WlPointerButtonState::Pressed
+                } else {
+                    WlPointerButtonState::Released
+                };
+                let serial = self.next_serial();
+                for (pointer_id, _) in pointers {
+                    self.event(pointer_id, 3)
+                        .uint(serial)
+                        .uint(time)
+                        .uint(button)
+                        .uint(state as u32);
+                }
+            }
+            PointerEvent::Axis { time, axis, value } => {
+                if self.pointer.focus.is_none() {
+                    return Ok(());
+                }
+                for (pointer_id, _) in pointers {
+                    self.event(pointer_id, 4)
+                        .uint(time)
+                        .uint(axis as u32)
+                        .fixed(value);
+                }
+            }
+            PointerEvent::Frame => {
+                for (pointer_id, version) in pointers {
+                    if version >= 5 {
+                        self.event(pointer_id, 5);
+                    }
+                }
+            }
+        }
+        self.flush_if_full().await
+    }
+
+    /// Tells a pointer created while the client already has focus which
+    /// surface it is over.
+    pub async fn send_wl_pointer_focus(
+        &mut self,
+        pointer_id: u32,
+        version: u32,
+    ) -> anyhow::Result<()> {
+        let Some(surface_id) = self.pointer.focus else {
+            return Ok(());
+        };
+        let serial = self.pointer.enter_serial;
+        let (x, y) = self.pointer.position;
+        self.event(pointer_id, 0)
+            .uint(serial)
+            .uint(surface_id)
+            .fixed(x)
+            .fixed(y);
+        if version >= 5 {
+            self.event(pointer_id, 5);
+        }
+        self.flush_if_full().await
+    }
+}
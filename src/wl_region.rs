@@ -1,16 +1,27 @@
 #![allow(dead_code)]
 
 use crate::CompositorClientState;
-use tracing::warn;
+use tracing::debug;
 
 impl<'a> CompositorClientState<'a> {
     pub async fn handle_wl_region_message(
         &mut self,
         object_id: u32,
         op_code: u16,
-        arg_bytes: &[u8],
+        _arg_bytes: &[u8],
     ) -> anyhow::Result<()> {
-        warn!("Unknown op_code {} for wl_region", op_code);
+        match op_code {
+            0 => self.handle_wl_region_destroy(object_id).await?,
+            // Regions aren't used for anything yet, so their contents aren't kept
+            1 | 2 => debug!("WlRegion request {} ignored for id {}", op_code, object_id),
+            _ => self.invalid_method(object_id, op_code).await?,
+        }
+        Ok(())
+    }
+
+    pub async fn handle_wl_region_destroy(&mut self, object_id: u32) -> anyhow::Result<()> {
+        debug!("WlRegion.destroy called for id {}", object_id);
+        self.object_registry.remove(&object_id);
         Ok(())
     }
 }
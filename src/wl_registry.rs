@@ -1,81 +1,104 @@
 #![allow(dead_code)]
 
 use crate::{
-    CompositorClientState, CompositorGlobalState, WaylandObject, utils::get_wayland_string_bytes,
+    CompositorClientState, WaylandObject, wire::ArgReader, wl_display::WlDisplayError,
     wl_shm::WlShmFormat,
 };
-use futures::lock::MutexGuard;
 use tracing::{debug, warn};
 
 impl<'a> CompositorClientState<'a> {
     pub async fn handle_wl_registry_message(
         &mut self,
+        object_id: u32,
         op_code: u16,
         arg_bytes: &[u8],
-        global_state: MutexGuard<'_, CompositorGlobalState>,
     ) -> anyhow::Result<()> {
         match op_code {
-            0 => {
-                self.handle_wl_registry_bind(arg_bytes, global_state)
-                    .await?
-            }
-            _ => {
-                warn!("Unknown op_code {} for wl_registry", op_code);
-            }
+            0 => self.handle_wl_registry_bind(object_id, arg_bytes).await?,
+            _ => self.invalid_method(object_id, op_code).await?,
         }
         Ok(())
     }
 
     pub async fn handle_wl_registry_bind(
         &mut self,
+        object_id: u32,
         arg_bytes: &[u8],
-        global_state: MutexGuard<'_, CompositorGlobalState>,
     ) -> anyhow::Result<()> {
-        let name = u32::from_le_bytes(arg_bytes[0..4].try_into().unwrap());
-        let iface_len = u32::from_le_bytes(arg_bytes[4..8].try_into().unwrap()) as usize;
-        let padded_len = (iface_len + 3) & !3;
-        let interface = String::from_utf8(arg_bytes[8..8 + iface_len - 1].to_vec()).unwrap();
-
-        let version = u32::from_le_bytes(
-            arg_bytes[8 + padded_len..12 + padded_len]
-                .try_into()
-                .unwrap(),
-        );
-        let new_id = u32::from_le_bytes(
-            arg_bytes[12 + padded_len..16 + padded_len]
-                .try_into()
-                .unwrap(),
-        );
+        let mut args = ArgReader::new(arg_bytes);
+        let name = args.uint()?;
+        let interface = args.string()?;
+        let version = args.uint()?;
+        let new_id = args.uint()?;
         debug!(
             "Registry bind called with name={}, new_id=({}::{}:{})",
             name, interface, version, new_id
         );
 
-        if let Some((_, interface, version)) =
-            global_state.globals.iter().find(|(n, _, _)| *n == name)
-        {
-            let object = match interface {
-                WaylandObject::WlShm => WaylandObject::WlShm,
-                WaylandObject::XdgWmBase => WaylandObject::XdgWmBase,
-                WaylandObject::WlCompositor => WaylandObject::WlCompositor,
-                _ => {
-                    anyhow::bail!("Unknown interface requested from globals: {}", interface);
-                }
-            };
-
-            if let WaylandObject::WlShm = object {
-                self.send_format(new_id, WlShmFormat::Argb8888 as u32)
-                    .await?;
-                self.send_format(new_id, WlShmFormat::Rgb888 as u32).await?;
+        let global = self
+            .globals
+            .load()
+            .iter()
+            .find(|(n, _, _)| *n == name)
+            .map(|(_, interface, advertised)| (*interface, *advertised));
+        // Like libwayland's global filter, a global hidden by policy can't be
+        // told apart from one that doesn't exist
+        let global = match global {
+            None => Err(format!("invalid global {} ({})", interface, name)),
+            Some((global, _)) if !self.global_allowed(global) => {
+                warn!(
+                    "Policy denied client {} ({:?}) binding {}",
+                    self.client_id,
+                    self.executable,
+                    global.as_str()
+                );
+                Err(format!("invalid global {} ({})", interface, name))
             }
+            Some((global, _)) if global.as_str() != interface => Err(format!(
+                "invalid interface for global {}: have {}, wanted {}",
+                name,
+                global.as_str(),
+                interface
+            )),
+            Some((global, advertised)) if version == 0 || version > advertised => Err(format!(
+                "invalid version for global {} ({}): have {}, wanted {}",
+                global.as_str(),
+                name,
+                advertised,
+                version
+            )),
+            Some((global, _)) => Ok(global),
+        };
+        let global = match global {
+            Ok(global) => global,
+            Err(message) => {
+                self.send_wl_display_error(
+                    object_id,
+                    WlDisplayError::InvalidObject as u32,
+                    &message,
+                )
+                .await?;
+                anyhow::bail!("Client sent {}", message);
+            }
+        };
 
-            self.object_registry.insert(new_id, object);
-            debug!(
-                "Bound new object id {} for interface {} version {}",
-                new_id, interface, version
-            );
-        } else {
-            warn!("No global found with name {}", name);
+        let object = global.bind(version);
+        let is_shm = matches!(object, WaylandObject::WlShm);
+        let is_seat = matches!(object, WaylandObject::WlSeat { .. });
+        debug!(
+            "Bound new object id {} for interface {} version {}",
+            new_id, object, version
+        );
+        self.insert_new_object(object_id, new_id, object).await?;
+        if is_shm {
+            self.send_format(new_id, WlShmFormat::Argb8888 as u32)
+                .await?;
+            self.send_format(new_id, WlShmFormat::Xrgb8888 as u32)
+                .await?;
+            self.send_format(new_id, WlShmFormat::Rgb888 as u32).await?;
+        }
+        if is_seat {
+            self.send_wl_seat_info(new_id, version).await?;
         }
         Ok(())
     }
@@ -87,18 +110,15 @@ impl<'a> CompositorClientState<'a> {
         interface: &str,
         version: u32,
     ) -> anyhow::Result<()> {
-        let mut args = Vec::new();
-        args.extend_from_slice(&name.to_le_bytes());
-
-        let interface_bytes = get_wayland_string_bytes(interface);
-        args.extend_from_slice(&interface_bytes);
-        args.extend_from_slice(&version.to_le_bytes());
-
         debug!(
             "Sending global {} (interface: {}, version: {}) to registry id {}",
             name, interface, version, registry_id
         );
 
-        self.send_message(registry_id, 0, &args).await
+        self.event(registry_id, 0)
+            .uint(name)
+            .string(interface)
+            .uint(version);
+        self.flush_if_full().await
     }
 }
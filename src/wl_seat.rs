@@ -0,0 +1,168 @@
+#![allow(dead_code)]
+
+use crate::{CompositorClientState, WaylandObject, wire::ArgReader};
+use tracing::debug;
+
+/// Highest wl_seat version advertised; wl_pointer, wl_keyboard and wl_touch
+/// objects take the version of the seat they were created from.
+pub const WL_SEAT_VERSION: u32 = 7;
+
+#[derive(Clone, Copy)]
+#[repr(u32)]
+pub enum WlSeatCapability {
+    Pointer = 1,
+    Keyboard = 2,
+    Touch = 4,
+}
+
+#[derive(Clone, Copy)]
+#[repr(u32)]
+pub enum WlSeatError {
+    MissingCapability = 0,
+}
+
+/// The compositor's one seat, shared by every client.
+///
+/// It always has a pointer, and a keyboard whenever the configured keymap
+/// compiles. Both are driven over IPC when no input device is attached.
+pub struct SeatState {
+    pub name: String,
+    /// Bitmask of [`WlSeatCapability`] the seat has now.
+    pub capabilities: u32,
+    /// Every capability the seat has ever had; requesting a device for any
+    /// other is a protocol error.
+    pub capabilities_ever: u32,
+}
+
+impl Default for SeatState {
+    fn default() -> Self {
+        SeatState {
+            name: "seat0".to_string(),
+            capabilities: WlSeatCapability::Pointer as u32,
+            capabilities_ever: WlSeatCapability::Pointer as u32,
+        }
+    }
+}
+
+impl<'a> CompositorClientState<'a> {
+    pub async fn handle_wl_seat_message(
+        &mut self,
+        object_id: u32,
+        op_code: u16,
+        arg_bytes: &[u8],
+        version: u32,
+    ) -> anyhow::Result<()> {
+        match op_code {
+            0 => {
+                self.handle_wl_seat_get_device(
+                    object_id,
+                    arg_bytes,
+                    WlSeatCapability::Pointer,
+                    WaylandObject::WlPointer { version },
+                )
+                .await?
+            }
+            1 => {
+                self.handle_wl_seat_get_device(
+                    object_id,
+                    arg_bytes,
+                    WlSeatCapability::Keyboard,
+                    WaylandObject::WlKeyboard { version },
+                )
+                .await?
+            }
+            2 => {
+                self.handle_wl_seat_get_device(
+                    object_id,
+                    arg_bytes,
+                    WlSeatCapability::Touch,
+                    WaylandObject::WlTouch { version },
+                )
+                .await?
+            }
+            3 if version >= 5 => self.handle_wl_seat_release(object_id).await?,
+            _ => self.invalid_method(object_id, op_code).await?,
+        }
+        Ok(())
+    }
+
+    /// Creates a wl_pointer, wl_keyboard or wl_touch.
+    ///
+    /// The device is created even if the seat has since lost the
+    /// capability, as a client may not have seen that change yet; it then
+    /// just never receives events. Keyboards get the keymap right away, and
+    /// both pointers and keyboards get the client's current focus.
+    async fn handle_wl_seat_get_device(
+        &mut self,
+        object_id: u32,
+        arg_bytes: &[u8],
+        capability: WlSeatCapability,
+        device: WaylandObject,
+    ) -> anyhow::Result<()> {
+        let mut args = ArgReader::new(arg_bytes);
+        let new_id = args.uint()?;
+        debug!(
+            "WlSeat get {} called with new_id {}",
+            device.as_str(),
+            new_id
+        );
+        let capabilities_ever = self
+            .global_state
+            .read()
+            .map_or(0, |global_state| global_state.seat.capabilities_ever);
+        if capabilities_ever & capability as u32 == 0 {
+            let message = format!("seat has never had a {}", device.as_str());
+            self.send_wl_display_error(object_id, WlSeatError::MissingCapability as u32, &message)
+                .await?;
+            anyhow::bail!("Client requested a {} without the capability", device);
+        }
+        let version = match device {
+            WaylandObject::WlPointer { version }
+            | WaylandObject::WlKeyboard { version }
+            | WaylandObject::WlTouch { version } => version,
+            _ => 0,
+        };
+        self.insert_new_object(object_id, new_id, device).await?;
+        match capability {
+            WlSeatCapability::Pointer => self.send_wl_pointer_focus(new_id, version).await,
+            WlSeatCapability::Keyboard => self.send_wl_keyboard_info(new_id, version).await,
+            WlSeatCapability::Touch => Ok(()),
+        }
+    }
+
+    pub async fn handle_wl_seat_release(&mut self, object_id: u32) -> anyhow::Result<()> {
+        debug!("WlSeat.release called for id {}", object_id);
+        self.object_registry.remove(&object_id);
+        Ok(())
+    }
+
+    /// Tells every wl_seat of the client that the capabilities changed.
+    pub async fn send_wl_seat_capabilities(&mut self, capabilities: u32) -> anyhow::Result<()> {
+        let seats = self
+            .object_registry
+            .iter()
+            .filter(|(_, object)| matches!(object, WaylandObject::WlSeat { .. }))
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for seat_id in seats {
+            self.event(seat_id, 0).uint(capabilities);
+        }
+        self.flush_if_full().await
+    }
+
+    /// Sends the seat's capabilities and, from version 2, its name.
+    pub async fn send_wl_seat_info(&mut self, seat_id: u32, version: u32) -> anyhow::Result<()> {
+        let (capabilities, name) = match self.global_state.read() {
+            Ok(global_state) => (
+                global_state.seat.capabilities,
+                global_state.seat.name.clone(),
+            ),
+            Err(_) => (0, String::new()),
+        };
+        self.event(seat_id, 0).uint(capabilities);
+        if version >= 2 {
+            self.event(seat_id, 1).string(&name);
+        }
+        self.flush_if_full().await
+    }
+}
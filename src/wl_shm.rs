@@ -1,19 +1,48 @@
 #![allow(dead_code)]
 
-use crate::{CompositorClientState, WaylandObject};
+use crate::{
+    CompositorClientState, WaylandObject,
+    wire::{ArgReader, ArgumentError},
+    wl_display::WlDisplayError,
+    wl_shm_pool::ShmPool,
+};
 use futures::lock::Mutex;
-use memmap2::MmapOptions;
-use std::{collections::VecDeque, sync::Arc};
-use tracing::{debug, warn};
+use std::{
+    collections::VecDeque,
+    os::fd::{FromRawFd, OwnedFd},
+    sync::Arc,
+};
+use tracing::debug;
 
 #[derive(Default, Clone, Copy)]
 #[repr(u32)]
 pub enum WlShmFormat {
     #[default]
     Argb8888 = 0,
+    Xrgb8888 = 1,
     Rgb888 = 0x34324752,
 }
 
+impl WlShmFormat {
+    /// Bytes per pixel of a supported format code, or `None` if unsupported.
+    pub fn bytes_per_pixel(format: u32) -> Option<usize> {
+        match format {
+            f if f == WlShmFormat::Argb8888 as u32 || f == WlShmFormat::Xrgb8888 as u32 => Some(4),
+            f if f == WlShmFormat::Rgb888 as u32 => Some(3),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(u32)]
+#[allow(clippy::enum_variant_names)]
+pub enum WlShmError {
+    InvalidFormat = 0,
+    InvalidStride = 1,
+    InvalidFd = 2,
+}
+
 impl<'a> CompositorClientState<'a> {
     pub async fn handle_wl_shm_message(
         &mut self,
@@ -23,36 +52,66 @@ impl<'a> CompositorClientState<'a> {
         fds: &mut VecDeque<i32>,
     ) -> anyhow::Result<()> {
         match op_code {
-            0 => self.handle_wl_shm_create_pool(arg_bytes, fds).await?,
+            0 => {
+                self.handle_wl_shm_create_pool(object_id, arg_bytes, fds)
+                    .await?
+            }
             // wl_shm.release()
             1 => self.handle_wl_shm_release(object_id).await?,
-            _ => {
-                warn!("Unknown op_code {} for wl_shm", op_code);
-            }
+            _ => self.invalid_method(object_id, op_code).await?,
         }
         Ok(())
     }
 
     pub async fn handle_wl_shm_create_pool(
         &mut self,
+        object_id: u32,
         arg_bytes: &[u8],
         fds: &mut VecDeque<i32>,
     ) -> anyhow::Result<()> {
         debug!("Shm.create_pool called");
-        let new_id = u32::from_le_bytes(arg_bytes[0..4].try_into().unwrap());
-        let size = i32::from_le_bytes(arg_bytes[4..8].try_into().unwrap());
-        let fd = fds.pop_front();
+        let mut args = ArgReader::new(arg_bytes);
+        let new_id = args.uint()?;
+        let size = args.int()?;
+        // Take ownership so the fd is closed once the pool is gone
+        let fd = fds
+            .pop_front()
+            .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+            .ok_or(ArgumentError::MissingFd)?;
 
-        if let Some(fd) = fd {
-            // mmap size bytes of the passed in fd
-            let mmap = unsafe { MmapOptions::new().len(size as usize).map_mut(fd)? };
-            self.object_registry.insert(
-                new_id,
-                WaylandObject::WlShmPool(Arc::new(Mutex::new(mmap)), fd),
+        // libwayland reports a size that can't be mapped as invalid_stride
+        if size <= 0 {
+            let message = format!("invalid size ({})", size);
+            self.send_wl_display_error(object_id, WlShmError::InvalidStride as u32, &message)
+                .await?;
+            anyhow::bail!("Client created a shm pool of size {}", size);
+        }
+        if self.usage.shm_bytes() + size as usize > self.limits.max_shm_bytes {
+            self.send_wl_display_error(
+                object_id,
+                WlDisplayError::NoMemory as u32,
+                "shm memory limit exceeded",
+            )
+            .await?;
+            anyhow::bail!(
+                "Client exceeded the shm limit of {} bytes",
+                self.limits.max_shm_bytes
             );
-        } else {
-            anyhow::bail!("No file descriptor provided for shm pool creation");
         }
+        let pool = match ShmPool::new(fd, size as usize, self.usage.clone()) {
+            Ok(pool) => pool,
+            Err(e) => {
+                self.send_wl_display_error(object_id, WlShmError::InvalidFd as u32, &e.to_string())
+                    .await?;
+                return Err(e);
+            }
+        };
+        self.insert_new_object(
+            object_id,
+            new_id,
+            WaylandObject::WlShmPool(Arc::new(Mutex::new(pool))),
+        )
+        .await?;
         Ok(())
     }
 
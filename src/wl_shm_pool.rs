@@ -1,10 +1,108 @@
 #![allow(dead_code)]
 
-use crate::{CompositorClientState, WaylandObject, wl_buffer::BufferState};
+use crate::{
+    CompositorClientState, WaylandObject,
+    accounting::ClientUsage,
+    sigbus,
+    wire::ArgReader,
+    wl_buffer::BufferState,
+    wl_display::WlDisplayError,
+    wl_shm::{WlShmError, WlShmFormat},
+};
 use futures::lock::Mutex;
-use memmap2::{MmapMut, RemapOptions};
-use std::sync::Arc;
-use tracing::{debug, warn};
+use memmap2::{MmapMut, MmapOptions, RemapOptions};
+use std::{
+    os::fd::{AsRawFd, OwnedFd},
+    sync::Arc,
+};
+use tracing::debug;
+
+/// The storage behind a wl_shm_pool.
+///
+/// The pool object and every buffer created from it hold an `Arc` to the same
+/// `ShmPool`, so the mapping and fd outlive `wl_shm_pool.destroy` until the
+/// last buffer is destroyed, at which point dropping it unmaps and closes.
+/// Buffers only reach the memory through the mutex, so a resize that moves the
+/// mapping is observed by all of them.
+///
+/// Reads go through [`ShmPool::access`] so that a client truncating the
+/// backing file can't SIGBUS the compositor. Pools backed by a memfd sealed
+/// against shrinking can skip the guard.
+pub struct ShmPool {
+    mmap: MmapMut,
+    fd: OwnedFd,
+    shrink_sealed: bool,
+    usage: Arc<ClientUsage>,
+}
+
+impl ShmPool {
+    pub fn new(fd: OwnedFd, size: usize, usage: Arc<ClientUsage>) -> anyhow::Result<Self> {
+        let file_size = file_size(&fd)?;
+        if file_size < size {
+            anyhow::bail!(
+                "Pool size {} exceeds the {} bytes backing the fd",
+                size,
+                file_size
+            );
+        }
+        let seals = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GET_SEALS) };
+        let shrink_sealed = seals >= 0 && seals & libc::F_SEAL_SHRINK != 0;
+
+        // mmap size bytes of the passed in fd
+        let mmap = unsafe { MmapOptions::new().len(size).map_mut(&fd)? };
+        usage.add_shm_bytes(size);
+        Ok(ShmPool {
+            mmap,
+            fd,
+            shrink_sealed,
+            usage,
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.mmap.len()
+    }
+
+    pub fn access<R>(&self, f: impl FnOnce(&[u8]) -> R) -> anyhow::Result<R> {
+        if self.shrink_sealed {
+            Ok(f(&self.mmap))
+        } else {
+            sigbus::guarded_access(&self.mmap, f)
+        }
+    }
+
+    /// Grows the mapping to `size` bytes, moving it if it can't grow in place.
+    pub fn resize(&mut self, size: usize) -> anyhow::Result<()> {
+        let file_size = file_size(&self.fd)?;
+        if file_size < size {
+            anyhow::bail!(
+                "Pool resize to {} exceeds the {} bytes backing the fd",
+                size,
+                file_size
+            );
+        }
+        let old_size = self.mmap.len();
+        unsafe {
+            self.mmap.remap(size, RemapOptions::new().may_move(true))?;
+        }
+        self.usage.add_shm_bytes(size - old_size);
+        Ok(())
+    }
+}
+
+impl Drop for ShmPool {
+    fn drop(&mut self) {
+        self.usage.remove_shm_bytes(self.mmap.len());
+    }
+}
+
+fn file_size(fd: &OwnedFd) -> anyhow::Result<usize> {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd.as_raw_fd(), &mut stat) } != 0 {
+        anyhow::bail!("Failed to stat shm fd: {}", std::io::Error::last_os_error());
+    }
+    Ok(stat.st_size as usize)
+}
 
 impl<'a> CompositorClientState<'a> {
     pub async fn handle_wl_shm_pool_message(
@@ -17,8 +115,8 @@ impl<'a> CompositorClientState<'a> {
             .object_registry
             .get(&object_id)
             .ok_or_else(|| anyhow::anyhow!("ShmPool object not found for id {}", object_id))?;
-        let mmap = match shm_pool_object {
-            WaylandObject::WlShmPool(mmap, _) => mmap,
+        let pool = match shm_pool_object {
+            WaylandObject::WlShmPool(pool) => pool,
             _ => {
                 anyhow::bail!("Object id {} is not a ShmPool", object_id);
             }
@@ -26,63 +124,125 @@ impl<'a> CompositorClientState<'a> {
 
         match op_code {
             0 => {
-                self.handle_wl_shm_pool_create_buffer(arg_bytes, mmap.clone())
+                self.handle_wl_shm_pool_create_buffer(object_id, arg_bytes, pool.clone())
                     .await?
             }
             1 => self.handle_wl_shm_pool_destroy(object_id).await?,
             2 => {
-                self.handle_wl_shm_pool_resize(arg_bytes, mmap.clone())
+                self.handle_wl_shm_pool_resize(object_id, arg_bytes, pool.clone())
                     .await?
             }
-            _ => {
-                warn!("Unknown op_code {} for ShmPool", op_code);
-            }
+            _ => self.invalid_method(object_id, op_code).await?,
         }
         Ok(())
     }
 
     pub async fn handle_wl_shm_pool_create_buffer(
         &mut self,
+        object_id: u32,
         arg_bytes: &[u8],
-        mmap: Arc<Mutex<MmapMut>>,
+        pool: Arc<Mutex<ShmPool>>,
     ) -> anyhow::Result<()> {
         debug!("ShmPool.create_buffer called");
-        let new_id = u32::from_le_bytes(arg_bytes[0..4].try_into().unwrap());
-        let offset = i32::from_le_bytes(arg_bytes[4..8].try_into().unwrap());
-        let width = i32::from_le_bytes(arg_bytes[8..12].try_into().unwrap());
-        let height = i32::from_le_bytes(arg_bytes[12..16].try_into().unwrap());
-        let stride = i32::from_le_bytes(arg_bytes[16..20].try_into().unwrap());
-        let format = u32::from_le_bytes(arg_bytes[20..24].try_into().unwrap());
+        if self.usage.buffers() >= self.limits.max_buffers {
+            self.send_wl_display_error(
+                object_id,
+                WlDisplayError::NoMemory as u32,
+                "buffer limit exceeded",
+            )
+            .await?;
+            anyhow::bail!(
+                "Client exceeded the limit of {} buffers",
+                self.limits.max_buffers
+            );
+        }
+        let mut args = ArgReader::new(arg_bytes);
+        let new_id = args.uint()?;
+        let offset = args.int()?;
+        let width = args.int()?;
+        let height = args.int()?;
+        let stride = args.int()?;
+        let format = args.uint()?;
+
+        let Some(bytes_per_pixel) = WlShmFormat::bytes_per_pixel(format) else {
+            let message = format!("invalid format {:#x}", format);
+            self.send_wl_display_error(object_id, WlShmError::InvalidFormat as u32, &message)
+                .await?;
+            anyhow::bail!("Client sent {}", message);
+        };
+        let pool_size = pool.lock().await.size() as i64;
+        let (offset_64, width_64, height_64, stride_64) =
+            (offset as i64, width as i64, height as i64, stride as i64);
+        if offset < 0
+            || width <= 0
+            || height <= 0
+            || stride_64 < width_64 * bytes_per_pixel as i64
+            || offset_64 + stride_64 * height_64 > pool_size
+        {
+            let message = format!(
+                "invalid width, height or stride ({}x{}, {}) at offset {}",
+                width, height, stride, offset
+            );
+            self.send_wl_display_error(object_id, WlShmError::InvalidStride as u32, &message)
+                .await?;
+            anyhow::bail!("Client sent {}", message);
+        }
+
         let buffer = BufferState {
             offset,
             width,
             height,
             stride,
             format,
-            shm_pool: mmap.clone(),
+            shm_pool: pool,
+            usage: self.usage.clone(),
         };
-        self.object_registry
-            .insert(new_id, WaylandObject::WlBuffer(buffer));
-        Ok(())
+        self.usage.add_buffer();
+        self.insert_new_object(object_id, new_id, WaylandObject::WlBuffer(buffer))
+            .await
     }
 
     pub async fn handle_wl_shm_pool_destroy(&mut self, object_id: u32) -> anyhow::Result<()> {
         debug!("ShmPool.destroy called for id {}", object_id);
+        // Buffers created from the pool keep its storage alive
         self.object_registry.remove(&object_id);
         Ok(())
     }
 
     pub async fn handle_wl_shm_pool_resize(
         &mut self,
+        object_id: u32,
         arg_bytes: &[u8],
-        mmap: Arc<Mutex<MmapMut>>,
+        pool: Arc<Mutex<ShmPool>>,
     ) -> anyhow::Result<()> {
-        debug!("ShmPool.resize called");
-        let new_size = u32::from_le_bytes(arg_bytes[0..4].try_into().unwrap());
-        let mut mmap = mmap.lock().await;
-        unsafe {
-            mmap.remap(new_size as usize, RemapOptions::new().may_move(false))?;
+        let mut args = ArgReader::new(arg_bytes);
+        let new_size = args.int()?;
+        debug!("ShmPool.resize called with size {}", new_size);
+        let mut pool = pool.lock().await;
+        if new_size < pool.size() as i32 {
+            drop(pool);
+            self.send_wl_display_error(
+                object_id,
+                WlShmError::InvalidStride as u32,
+                "shrinking pool invalid",
+            )
+            .await?;
+            anyhow::bail!("Client tried to shrink shm pool {}", object_id);
         }
-        Ok(())
+        let growth = new_size as usize - pool.size();
+        if self.usage.shm_bytes() + growth > self.limits.max_shm_bytes {
+            drop(pool);
+            self.send_wl_display_error(
+                object_id,
+                WlDisplayError::NoMemory as u32,
+                "shm memory limit exceeded",
+            )
+            .await?;
+            anyhow::bail!(
+                "Client exceeded the shm limit of {} bytes",
+                self.limits.max_shm_bytes
+            );
+        }
+        pool.resize(new_size as usize)
     }
 }
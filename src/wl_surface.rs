@@ -1,7 +1,21 @@
 #![allow(dead_code)]
 
-use crate::{CompositorClientState, WaylandObject, wl_output::WlOutputTransform};
-use tracing::{debug, warn};
+use crate::{
+    CompositorClientState, WaylandObject, logging::debug_sampled, texture::Texture,
+    wire::ArgReader, wl_output::WlOutputTransform, wl_shm::WlShmError,
+};
+use std::sync::PoisonError;
+use tracing::{Instrument, debug, debug_span};
+
+#[derive(Clone, Copy)]
+#[repr(u32)]
+pub enum WlSurfaceError {
+    InvalidScale = 0,
+    InvalidTransform = 1,
+    InvalidSize = 2,
+    InvalidOffset = 3,
+    DefunctRoleObject = 4,
+}
 
 #[derive(Default)]
 pub struct SurfaceState {
@@ -22,6 +36,36 @@ pub struct SurfaceState {
     pending_offset: (i32, i32),
     current_offset: (i32, i32),
     frame_callbacks: Vec<u32>,
+    texture: Texture,
+}
+
+impl SurfaceState {
+    /// Drops a pending attach of a buffer being destroyed; like libwayland,
+    /// the next commit then attaches no buffer. A committed buffer is
+    /// forgotten too, so its id can't be looked up once reused, while the
+    /// texture keeps showing its contents.
+    pub fn forget_buffer(&mut self, buffer_id: u32) {
+        if self.pending_buffer == Some(buffer_id) {
+            self.pending_buffer = Some(0);
+        }
+        if self.current_buffer == Some(buffer_id) {
+            self.current_buffer = None;
+        }
+    }
+
+    /// Size in surface-local coordinates, or `None` without contents.
+    fn size(&self) -> Option<(i32, i32)> {
+        if self.texture.width == 0 {
+            return None;
+        }
+        let scale = self.current_scale.max(1);
+        let (width, height) = (self.texture.width / scale, self.texture.height / scale);
+        if self.current_transform as i32 % 2 == 1 {
+            Some((height, width))
+        } else {
+            Some((width, height))
+        }
+    }
 }
 
 impl<'a> CompositorClientState<'a> {
@@ -45,7 +89,9 @@ impl<'a> CompositorClientState<'a> {
                     .await?
             }
             6 => {
-                self.handle_wl_surface_commit(object_id).await?;
+                self.handle_wl_surface_commit(object_id)
+                    .instrument(debug_span!("commit", surface_id = object_id))
+                    .await?;
             }
             7 => {
                 self.handle_wl_surface_set_buffer_transform(object_id, arg_bytes)
@@ -62,9 +108,7 @@ impl<'a> CompositorClientState<'a> {
             // wl_surface.offset(x:int, y:int)
             10 => self.handle_wl_surface_offset(object_id, arg_bytes).await?,
 
-            _ => {
-                warn!("Unknown op_code {} for wl_surface", op_code);
-            }
+            _ => self.invalid_method(object_id, op_code).await?,
         }
         Ok(())
     }
@@ -72,6 +116,13 @@ impl<'a> CompositorClientState<'a> {
     pub async fn handle_wl_surface_destroy(&mut self, object_id: u32) -> anyhow::Result<()> {
         debug!("WlSurface.destroy called for id {}", object_id);
         self.object_registry.remove(&object_id);
+        self.usage.remove_surface(object_id);
+        let mut global_state = self
+            .global_state
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        global_state.scene.unmap(self.client_id, object_id);
+        global_state.update_focus();
         Ok(())
     }
 
@@ -80,9 +131,14 @@ impl<'a> CompositorClientState<'a> {
         object_id: u32,
         arg_bytes: &[u8],
     ) -> anyhow::Result<()> {
-        let buffer_id = u32::from_le_bytes(arg_bytes[0..4].try_into().unwrap());
-        let x = i32::from_le_bytes(arg_bytes[4..8].try_into().unwrap());
-        let y = i32::from_le_bytes(arg_bytes[8..12].try_into().unwrap());
+        let mut args = ArgReader::new(arg_bytes);
+        let buffer_id = args.uint()?;
+        let x = args.int()?;
+        let y = args.int()?;
+        self.check_object_argument(object_id, buffer_id, true, |object| {
+            matches!(object, WaylandObject::WlBuffer(_))
+        })
+        .await?;
 
         let surface_object = self
             .object_registry
@@ -95,9 +151,11 @@ impl<'a> CompositorClientState<'a> {
             }
         };
 
-        debug!(
+        debug_sampled!(
             "WlSurface.attach called with buffer_id {}, x {}, y {}",
-            buffer_id, x, y
+            buffer_id,
+            x,
+            y
         );
         surface.pending_buffer = Some(buffer_id);
         Ok(())
@@ -108,10 +166,11 @@ impl<'a> CompositorClientState<'a> {
         object_id: u32,
         arg_bytes: &[u8],
     ) -> anyhow::Result<()> {
-        let x = i32::from_le_bytes(arg_bytes[0..4].try_into().unwrap());
-        let y = i32::from_le_bytes(arg_bytes[4..8].try_into().unwrap());
-        let width = i32::from_le_bytes(arg_bytes[8..12].try_into().unwrap());
-        let height = i32::from_le_bytes(arg_bytes[12..16].try_into().unwrap());
+        let mut args = ArgReader::new(arg_bytes);
+        let x = args.int()?;
+        let y = args.int()?;
+        let width = args.int()?;
+        let height = args.int()?;
 
         let surface_object = self
             .object_registry
@@ -124,9 +183,12 @@ impl<'a> CompositorClientState<'a> {
             }
         };
 
-        debug!(
+        debug_sampled!(
             "WlSurface.damage called with x {}, y {}, width {}, height {}",
-            x, y, width, height
+            x,
+            y,
+            width,
+            height
         );
         surface.pending_surface_damage.push((x, y, width, height));
         Ok(())
@@ -137,7 +199,10 @@ impl<'a> CompositorClientState<'a> {
         object_id: u32,
         arg_bytes: &[u8],
     ) -> anyhow::Result<()> {
-        let new_id = u32::from_le_bytes(arg_bytes[0..4].try_into().unwrap());
+        let mut args = ArgReader::new(arg_bytes);
+        let new_id = args.uint()?;
+        self.insert_new_object(object_id, new_id, WaylandObject::WlCallback)
+            .await?;
         let surface_object = self
             .object_registry
             .get_mut(&object_id)
@@ -149,10 +214,8 @@ impl<'a> CompositorClientState<'a> {
             }
         };
 
-        debug!("WlSurface.frame called with new_id {}", new_id);
+        debug_sampled!("WlSurface.frame called with new_id {}", new_id);
         surface.frame_callbacks.push(new_id);
-        self.object_registry
-            .insert(new_id, WaylandObject::WlCallback);
         Ok(())
     }
 
@@ -161,7 +224,12 @@ impl<'a> CompositorClientState<'a> {
         object_id: u32,
         arg_bytes: &[u8],
     ) -> anyhow::Result<()> {
-        let region_id = u32::from_le_bytes(arg_bytes[..4].try_into().unwrap());
+        let mut args = ArgReader::new(arg_bytes);
+        let region_id = args.uint()?;
+        self.check_object_argument(object_id, region_id, true, |object| {
+            matches!(object, WaylandObject::WlRegion)
+        })
+        .await?;
         let surface_object = self
             .object_registry
             .get_mut(&object_id)
@@ -186,7 +254,12 @@ impl<'a> CompositorClientState<'a> {
         object_id: u32,
         arg_bytes: &[u8],
     ) -> anyhow::Result<()> {
-        let region_id = u32::from_le_bytes(arg_bytes[..4].try_into().unwrap());
+        let mut args = ArgReader::new(arg_bytes);
+        let region_id = args.uint()?;
+        self.check_object_argument(object_id, region_id, true, |object| {
+            matches!(object, WaylandObject::WlRegion)
+        })
+        .await?;
         let surface_object = self
             .object_registry
             .get_mut(&object_id)
@@ -218,8 +291,16 @@ impl<'a> CompositorClientState<'a> {
             }
         };
 
-        debug!("WlSurface.commit called");
-        surface.current_buffer = surface.pending_buffer.take();
+        debug_sampled!("WlSurface.commit called");
+        self.usage.record_commit(object_id);
+        let attached = surface.pending_buffer.take();
+        if let Some(buffer_id) = attached {
+            surface.current_buffer = if buffer_id == 0 {
+                None
+            } else {
+                Some(buffer_id)
+            };
+        }
         surface.current_surface_damage = std::mem::take(&mut surface.pending_surface_damage);
         surface.current_buffer_damage = std::mem::take(&mut surface.pending_buffer_damage);
         surface.current_opaque_region = surface.pending_opaque_region.take();
@@ -228,9 +309,18 @@ impl<'a> CompositorClientState<'a> {
         surface.current_scale = surface.pending_scale;
         surface.current_offset = surface.pending_offset;
         // TODO: Rendering the surface would happen here
-        // TODO: Maybe release the buffer?
 
         let callback_ids = surface.frame_callbacks.drain(..).collect::<Vec<u32>>();
+        if let Some(buffer_id) = attached {
+            self.update_wl_surface_texture(object_id)
+                .instrument(debug_span!("upload", surface_id = object_id))
+                .await?;
+            // The texture holds a copy, so the client may reuse the buffer now
+            if buffer_id != 0 {
+                self.send_wl_buffer_release(buffer_id).await?;
+            }
+            self.update_wl_surface_scene(object_id);
+        }
         for callback_id in callback_ids {
             self.send_callback_done(callback_id, 0).await?;
         }
@@ -238,12 +328,110 @@ impl<'a> CompositorClientState<'a> {
         Ok(())
     }
 
+    /// Maps the surface for input at its current size, or unmaps it once it
+    /// has no buffer.
+    fn update_wl_surface_scene(&mut self, object_id: u32) {
+        let Some(WaylandObject::WlSurface(surface)) = self.object_registry.get(&object_id) else {
+            return;
+        };
+        let size = surface.size();
+        let mut global_state = self
+            .global_state
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        match size {
+            Some((width, height)) => {
+                global_state
+                    .scene
+                    .map(self.client_id, object_id, width, height)
+            }
+            None => global_state.scene.unmap(self.client_id, object_id),
+        }
+        global_state.update_focus();
+    }
+
+    /// Converts the damaged parts of the surface's current buffer into its texture.
+    async fn update_wl_surface_texture(&mut self, object_id: u32) -> anyhow::Result<()> {
+        let Some(WaylandObject::WlSurface(surface)) = self.object_registry.get_mut(&object_id)
+        else {
+            anyhow::bail!("Object id {} is not a WlSurface", object_id);
+        };
+        let Some(buffer_id) = surface.current_buffer else {
+            surface.texture = Texture::default();
+            return Ok(());
+        };
+
+        let mut damage = surface.current_buffer_damage.clone();
+        if let WlOutputTransform::Normal = surface.current_transform {
+            let scale = surface.current_scale.max(1);
+            damage.extend(surface.current_surface_damage.iter().map(|&(x, y, w, h)| {
+                (
+                    x.saturating_mul(scale),
+                    y.saturating_mul(scale),
+                    w.saturating_mul(scale),
+                    h.saturating_mul(scale),
+                )
+            }));
+        } else if !surface.current_surface_damage.is_empty() {
+            // Mapping surface damage through a transform isn't supported yet
+            damage.push((0, 0, i32::MAX, i32::MAX));
+        }
+        let mut texture = std::mem::take(&mut surface.texture);
+
+        let buffer = match self.object_registry.get(&buffer_id) {
+            Some(WaylandObject::WlBuffer(buffer)) => buffer,
+            _ => {
+                anyhow::bail!("Object id {} is not a WlBuffer", buffer_id);
+            }
+        };
+        let result = {
+            let pool = buffer.shm_pool.lock().await;
+            pool.access(|pool| texture.upload(buffer, pool, &damage))
+        };
+
+        if let Some(WaylandObject::WlSurface(surface)) = self.object_registry.get_mut(&object_id) {
+            surface.texture = texture;
+        }
+        match result {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => {
+                self.send_wl_display_error(
+                    buffer_id,
+                    WlShmError::InvalidStride as u32,
+                    &format!("invalid buffer: {}", e),
+                )
+                .await?;
+                Err(e)
+            }
+            Err(e) => {
+                self.send_wl_display_error(
+                    buffer_id,
+                    WlShmError::InvalidFd as u32,
+                    "error accessing SHM buffer",
+                )
+                .await?;
+                Err(e)
+            }
+        }
+    }
+
     pub async fn handle_wl_surface_set_buffer_transform(
         &mut self,
         object_id: u32,
         arg_bytes: &[u8],
     ) -> anyhow::Result<()> {
-        let transform = i32::from_le_bytes(arg_bytes[..4].try_into().unwrap());
+        let mut args = ArgReader::new(arg_bytes);
+        let transform = args.int()?;
+        let Ok(transform) = WlOutputTransform::try_from(transform) else {
+            let message = format!("buffer transform {} is not an output transform", transform);
+            self.send_wl_display_error(
+                object_id,
+                WlSurfaceError::InvalidTransform as u32,
+                &message,
+            )
+            .await?;
+            anyhow::bail!("Client set an invalid buffer transform {}", transform);
+        };
         let surface_object = self
             .object_registry
             .get_mut(&object_id)
@@ -257,10 +445,9 @@ impl<'a> CompositorClientState<'a> {
 
         debug!(
             "WlSurface.set_buffer_transform called with transform {}",
-            transform
+            transform as i32
         );
-        surface.pending_transform =
-            unsafe { std::mem::transmute::<i32, WlOutputTransform>(transform) };
+        surface.pending_transform = transform;
         Ok(())
     }
 
@@ -269,7 +456,14 @@ impl<'a> CompositorClientState<'a> {
         object_id: u32,
         arg_bytes: &[u8],
     ) -> anyhow::Result<()> {
-        let scale = i32::from_le_bytes(arg_bytes[..4].try_into().unwrap());
+        let mut args = ArgReader::new(arg_bytes);
+        let scale = args.int()?;
+        if scale <= 0 {
+            let message = format!("buffer scale {} is not positive", scale);
+            self.send_wl_display_error(object_id, WlSurfaceError::InvalidScale as u32, &message)
+                .await?;
+            anyhow::bail!("Client set an invalid buffer scale {}", scale);
+        }
         let surface_object = self
             .object_registry
             .get_mut(&object_id)
@@ -291,10 +485,11 @@ impl<'a> CompositorClientState<'a> {
         object_id: u32,
         arg_bytes: &[u8],
     ) -> anyhow::Result<()> {
-        let x = i32::from_le_bytes(arg_bytes[0..4].try_into().unwrap());
-        let y = i32::from_le_bytes(arg_bytes[4..8].try_into().unwrap());
-        let width = i32::from_le_bytes(arg_bytes[8..12].try_into().unwrap());
-        let height = i32::from_le_bytes(arg_bytes[12..16].try_into().unwrap());
+        let mut args = ArgReader::new(arg_bytes);
+        let x = args.int()?;
+        let y = args.int()?;
+        let width = args.int()?;
+        let height = args.int()?;
 
         let surface_object = self
             .object_registry
@@ -307,9 +502,12 @@ impl<'a> CompositorClientState<'a> {
             }
         };
 
-        debug!(
+        debug_sampled!(
             "WlSurface.damage_buffer called with x {}, y {}, width {}, height {}",
-            x, y, width, height
+            x,
+            y,
+            width,
+            height
         );
         surface.pending_buffer_damage.push((x, y, width, height));
         Ok(())
@@ -320,8 +518,9 @@ impl<'a> CompositorClientState<'a> {
         object_id: u32,
         arg_bytes: &[u8],
     ) -> anyhow::Result<()> {
-        let x = i32::from_le_bytes(arg_bytes[0..4].try_into().unwrap());
-        let y = i32::from_le_bytes(arg_bytes[4..8].try_into().unwrap());
+        let mut args = ArgReader::new(arg_bytes);
+        let x = args.int()?;
+        let y = args.int()?;
         let surface_object = self
             .object_registry
             .get_mut(&object_id)
@@ -380,3 +579,27 @@ pub async fn send_wl_surface_preferred_buffer_transform(
         .send_message(surface_id, 3, &transform_int.to_le_bytes())
         .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn destroyed_buffer_is_forgotten_but_its_contents_stay() {
+        let mut surface = SurfaceState {
+            pending_buffer: Some(8),
+            current_buffer: Some(7),
+            ..SurfaceState::default()
+        };
+        surface.texture.width = 4;
+        surface.texture.height = 2;
+
+        surface.forget_buffer(7);
+        assert_eq!(surface.current_buffer, None);
+        assert_eq!(surface.pending_buffer, Some(8));
+        assert_eq!(surface.size(), Some((4, 2)));
+
+        surface.forget_buffer(8);
+        assert_eq!(surface.pending_buffer, Some(0));
+    }
+}
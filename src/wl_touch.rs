@@ -0,0 +1,108 @@
+#![allow(dead_code)]
+
+use crate::{CompositorClientState, WaylandObject, input::TouchEvent};
+use std::collections::HashSet;
+use tracing::debug;
+
+/// Touch as one client sees it.
+#[derive(Default)]
+pub struct ClientTouch {
+    /// Ids of the client's touch points that are down. Points that went
+    /// down on an already destroyed surface aren't reported, nor is
+    /// anything else about them.
+    points: HashSet<i32>,
+}
+
+impl<'a> CompositorClientState<'a> {
+    pub async fn handle_wl_touch_message(
+        &mut self,
+        object_id: u32,
+        op_code: u16,
+        version: u32,
+    ) -> anyhow::Result<()> {
+        match op_code {
+            0 if version >= 3 => self.handle_wl_touch_release(object_id).await?,
+            _ => self.invalid_method(object_id, op_code).await?,
+        }
+        Ok(())
+    }
+
+    pub async fn handle_wl_touch_release(&mut self, object_id: u32) -> anyhow::Result<()> {
+        debug!("WlTouch.release called for id {}", object_id);
+        self.object_registry.remove(&object_id);
+        Ok(())
+    }
+
+    /// `object_id` of every wl_touch the client has.
+    fn wl_touches(&self) -> Vec<u32> {
+        self.object_registry
+            .iter()
+            .filter_map(|(&id, object)| match object {
+                WaylandObject::WlTouch { .. } => Some(id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Turns touch input routed to this client into events on its wl_touch
+    /// objects.
+    pub async fn handle_touch_event(&mut self, event: TouchEvent) -> anyhow::Result<()> {
+        let touches = self.wl_touches();
+        match event {
+            TouchEvent::Down {
+                time,
+                surface_id,
+                id,
+                x,
+                y,
+            } => {
+                if !matches!(
+                    self.object_registry.get(&surface_id),
+                    Some(WaylandObject::WlSurface(_))
+                ) {
+                    return Ok(());
+                }
+                self.touch.points.insert(id);
+                let serial = self.next_serial();
+                for touch_id in touches {
+                    self.event(touch_id, 0)
+                        .uint(serial)
+                        .uint(time)
+                        .uint(surface_id)
+                        .int(id)
+                        .fixed(x)
+                        .fixed(y);
+                }
+            }
+            TouchEvent::Up { time, id } => {
+                if !self.touch.points.remove(&id) {
+                    return Ok(());
+                }
+                let serial = self.next_serial();
+                for touch_id in touches {
+                    self.event(touch_id, 1).uint(serial).uint(time).int(id);
+                }
+            }
+            TouchEvent::Motion { time, id, x, y } => {
+                if !self.touch.points.contains(&id) {
+                    return Ok(());
+                }
+                for touch_id in touches {
+                    self.event(touch_id, 2).uint(time).int(id).fixed(x).fixed(y);
+                }
+            }
+            TouchEvent::Frame => {
+                for touch_id in touches {
+                    self.event(touch_id, 3);
+                }
+            }
+            TouchEvent::Cancel => {
+                self.touch.points.clear();
+                for touch_id in touches {
+                    self.event(touch_id, 4);
+                }
+            }
+        }
+        self.flush_if_full().await
+    }
+}
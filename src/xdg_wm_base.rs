@@ -1,7 +1,21 @@
 #![allow(dead_code)]
 
-use crate::CompositorClientState;
-use tracing::warn;
+use crate::{CompositorClientState, WaylandObject, wire::ArgReader};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+#[derive(Default)]
+pub struct XdgWmBaseState {
+    pending_ping: Option<(u32, Instant)>,
+}
+
+impl XdgWmBaseState {
+    /// Whether a ping has gone unanswered for `timeout` or longer.
+    pub fn is_unresponsive(&self, timeout: Duration) -> bool {
+        self.pending_ping
+            .is_some_and(|(_, sent)| sent.elapsed() >= timeout)
+    }
+}
 
 impl<'a> CompositorClientState<'a> {
     pub async fn handle_xdg_wm_base_message(
@@ -10,7 +24,112 @@ impl<'a> CompositorClientState<'a> {
         op_code: u16,
         arg_bytes: &[u8],
     ) -> anyhow::Result<()> {
-        warn!("Unknown op_code {} for xdg_wm_base", op_code);
+        match op_code {
+            0 => self.handle_xdg_wm_base_destroy(object_id).await?,
+            3 => self.handle_xdg_wm_base_pong(object_id, arg_bytes).await?,
+            1 | 2 => {
+                warn!("xdg_wm_base request {} is not implemented", op_code);
+            }
+            _ => self.invalid_method(object_id, op_code).await?,
+        }
+        Ok(())
+    }
+
+    pub async fn handle_xdg_wm_base_destroy(&mut self, object_id: u32) -> anyhow::Result<()> {
+        debug!("XdgWmBase.destroy called for id {}", object_id);
+        self.object_registry.remove(&object_id);
+        Ok(())
+    }
+
+    pub async fn handle_xdg_wm_base_pong(
+        &mut self,
+        object_id: u32,
+        arg_bytes: &[u8],
+    ) -> anyhow::Result<()> {
+        let mut args = ArgReader::new(arg_bytes);
+        let serial = args.uint()?;
+        let wm_base = match self.object_registry.get_mut(&object_id) {
+            Some(WaylandObject::XdgWmBase(wm_base)) => wm_base,
+            _ => {
+                anyhow::bail!("Object id {} is not an XdgWmBase", object_id);
+            }
+        };
+
+        debug!("XdgWmBase.pong called with serial {}", serial);
+        match wm_base.pending_ping {
+            Some((pending, _)) if pending == serial => wm_base.pending_ping = None,
+            _ => warn!("Pong with unexpected serial {} for xdg_wm_base", serial),
+        }
+        if self.usage.unresponsive() && !self.is_client_unresponsive() {
+            debug!("Client is responsive again");
+            self.usage.set_unresponsive(false);
+        }
+        Ok(())
+    }
+
+    /// Sends a ping unless one is already outstanding for this xdg_wm_base.
+    pub async fn send_xdg_wm_base_ping(&mut self, object_id: u32) -> anyhow::Result<()> {
+        match self.object_registry.get(&object_id) {
+            Some(WaylandObject::XdgWmBase(wm_base)) if wm_base.pending_ping.is_some() => {
+                return Ok(());
+            }
+            Some(WaylandObject::XdgWmBase(_)) => {}
+            _ => {
+                anyhow::bail!("Object id {} is not an XdgWmBase", object_id);
+            }
+        }
+        let serial = self.next_serial();
+        if let Some(WaylandObject::XdgWmBase(wm_base)) = self.object_registry.get_mut(&object_id) {
+            wm_base.pending_ping = Some((serial, Instant::now()));
+        }
+
+        debug!("Sending xdg_wm_base ping {} to id {}", serial, object_id);
+        self.send_message(object_id, 0, &serial.to_le_bytes()).await
+    }
+
+    /// Whether any xdg_wm_base bound by this client has an expired ping.
+    pub fn is_client_unresponsive(&self) -> bool {
+        self.object_registry.values().any(|object| match object {
+            WaylandObject::XdgWmBase(wm_base) => wm_base.is_unresponsive(self.ping_timeout),
+            _ => false,
+        })
+    }
+
+    /// When the oldest outstanding ping expires, unless the client is
+    /// already reported unresponsive.
+    pub fn ping_deadline(&self) -> Option<Instant> {
+        if self.usage.unresponsive() {
+            return None;
+        }
+        self.object_registry
+            .values()
+            .filter_map(|object| match object {
+                WaylandObject::XdgWmBase(wm_base) => wm_base.pending_ping,
+                _ => None,
+            })
+            .map(|(_, sent)| sent + self.ping_timeout)
+            .min()
+    }
+
+    /// Reports the client unresponsive once a ping has expired.
+    pub fn check_xdg_wm_base_pings(&mut self) {
+        if self.is_client_unresponsive() && !self.usage.unresponsive() {
+            warn!("Client has not answered a ping in time, reporting it unresponsive");
+            self.usage.set_unresponsive(true);
+        }
+    }
+
+    /// Pings every xdg_wm_base that has no ping outstanding.
+    pub async fn ping_xdg_wm_bases(&mut self) -> anyhow::Result<()> {
+        let wm_base_ids = self
+            .object_registry
+            .iter()
+            .filter(|(_, object)| matches!(object, WaylandObject::XdgWmBase(_)))
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for wm_base_id in wm_base_ids {
+            self.send_xdg_wm_base_ping(wm_base_id).await?;
+        }
         Ok(())
     }
 }
@@ -0,0 +1,205 @@
+//! Keymaps and modifier state from libxkbcommon.
+//!
+//! The library is loaded at runtime rather than linked, so the compositor
+//! builds without its development files and still runs without it, just
+//! without a keyboard.
+
+use crate::config::KeyboardConfig;
+use std::{
+    ffi::{CStr, CString},
+    ptr,
+};
+use xkbcommon_dl::{
+    XkbCommon, xkb_context_flags, xkb_key_direction, xkb_keymap, xkb_keymap_compile_flags,
+    xkb_keymap_format, xkb_rule_names, xkb_state, xkb_state_component, xkbcommon_option,
+};
+
+/// xkb keycodes are evdev codes offset by 8, a holdover from X11.
+const EVDEV_OFFSET: u32 = 8;
+
+fn library() -> anyhow::Result<&'static XkbCommon> {
+    xkbcommon_option().ok_or_else(|| anyhow::anyhow!("Failed to load libxkbcommon"))
+}
+
+/// A compiled keymap.
+pub struct Keymap {
+    keymap: *mut xkb_keymap,
+}
+
+// SAFETY: libxkbcommon never modifies a keymap after compiling it and
+// counts references atomically, so it may be shared and dropped from any
+// thread.
+unsafe impl Send for Keymap {}
+// SAFETY: every method takes `&self` and only reads the immutable keymap.
+unsafe impl Sync for Keymap {}
+
+impl Keymap {
+    /// Compiles the keymap named by `config`.
+    pub fn new(config: &KeyboardConfig) -> anyhow::Result<Keymap> {
+        let library = library()?;
+        let name = |name: &str| (!name.is_empty()).then(|| CString::new(name)).transpose();
+        let rules = name(&config.rules)?;
+        let model = name(&config.model)?;
+        let layout = name(&config.layout)?;
+        let variant = name(&config.variant)?;
+        let options = name(&config.options)?;
+        let as_ptr =
+            |name: &Option<CString>| name.as_ref().map_or(ptr::null(), |name| name.as_ptr());
+        let names = xkb_rule_names {
+            rules: as_ptr(&rules),
+            model: as_ptr(&model),
+            layout: as_ptr(&layout),
+            variant: as_ptr(&variant),
+            options: as_ptr(&options),
+        };
+
+        // SAFETY: `names` points at strings that outlive the call, and the
+        // keymap holds its own reference to the context
+        let keymap = unsafe {
+            let context = (library.xkb_context_new)(xkb_context_flags::XKB_CONTEXT_NO_FLAGS);
+            if context.is_null() {
+                anyhow::bail!("Failed to create an xkb context");
+            }
+            let keymap = (library.xkb_keymap_new_from_names)(
+                context,
+                &names,
+                xkb_keymap_compile_flags::XKB_KEYMAP_COMPILE_NO_FLAGS,
+            );
+            (library.xkb_context_unref)(context);
+            keymap
+        };
+        if keymap.is_null() {
+            anyhow::bail!(
+                "Failed to compile keymap for layout {:?} variant {:?}",
+                config.layout,
+                config.variant
+            );
+        }
+        Ok(Keymap { keymap })
+    }
+
+    /// The keymap in the text format clients compile it from.
+    pub fn as_string(&self) -> anyhow::Result<String> {
+        let library = library()?;
+        // SAFETY: the string is NUL-terminated and ours to free
+        unsafe {
+            let string = (library.xkb_keymap_get_as_string)(
+                self.keymap,
+                xkb_keymap_format::XKB_KEYMAP_FORMAT_TEXT_V1,
+            );
+            if string.is_null() {
+                anyhow::bail!("Failed to serialize keymap");
+            }
+            let text = CStr::from_ptr(string).to_string_lossy().into_owned();
+            libc::free(string.cast_mut().cast());
+            Ok(text)
+        }
+    }
+}
+
+impl Drop for Keymap {
+    fn drop(&mut self) {
+        if let Ok(library) = library() {
+            // SAFETY: releases the reference taken when the keymap compiled
+            unsafe { (library.xkb_keymap_unref)(self.keymap) };
+        }
+    }
+}
+
+/// Modifier and layout state in wl_keyboard.modifiers form.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub depressed: u32,
+    pub latched: u32,
+    pub locked: u32,
+    pub group: u32,
+}
+
+/// Tracks which modifiers held and locked keys add up to.
+pub struct KeyState {
+    state: *mut xkb_state,
+    /// Kept alive by the reference `state` holds.
+    keymap: *mut xkb_keymap,
+}
+
+// SAFETY: the state is owned by this value alone, and libxkbcommon keeps no
+// thread-local data for it, so it may move to another thread.
+unsafe impl Send for KeyState {}
+// SAFETY: the only mutation, `update_key`, takes `&mut self`; the `&self`
+// methods only read the state, which is safe to do concurrently.
+unsafe impl Sync for KeyState {}
+
+impl KeyState {
+    /// Starts with no keys held; the state keeps its own reference to
+    /// `keymap`.
+    pub fn new(keymap: &Keymap) -> anyhow::Result<KeyState> {
+        let library = library()?;
+        // SAFETY: `keymap` is a live compiled keymap
+        let state = unsafe { (library.xkb_state_new)(keymap.keymap) };
+        if state.is_null() {
+            anyhow::bail!("Failed to create xkb state");
+        }
+        Ok(KeyState {
+            state,
+            keymap: keymap.keymap,
+        })
+    }
+
+    /// Presses or releases an evdev key, returning whether the modifiers
+    /// changed.
+    pub fn update_key(&mut self, key: u32, pressed: bool) -> bool {
+        let Ok(library) = library() else {
+            return false;
+        };
+        let direction = if pressed {
+            xkb_key_direction::XKB_KEY_DOWN
+        } else {
+            xkb_key_direction::XKB_KEY_UP
+        };
+        // SAFETY: `self.state` is live, and any keycode is accepted
+        let changed =
+            unsafe { (library.xkb_state_update_key)(self.state, key + EVDEV_OFFSET, direction) };
+        !changed.is_empty()
+    }
+
+    /// Whether the keymap has an evdev key repeat while held; modifiers
+    /// typically don't.
+    pub fn key_repeats(&self, key: u32) -> bool {
+        let Ok(library) = library() else {
+            return false;
+        };
+        // SAFETY: the state holds a reference to its keymap
+        unsafe { (library.xkb_keymap_key_repeats)(self.keymap, key + EVDEV_OFFSET) != 0 }
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        let Ok(library) = library() else {
+            return Modifiers::default();
+        };
+        let mods = |component| {
+            // SAFETY: `self.state` is live
+            unsafe { (library.xkb_state_serialize_mods)(self.state, component) }
+        };
+        Modifiers {
+            depressed: mods(xkb_state_component::XKB_STATE_MODS_DEPRESSED),
+            latched: mods(xkb_state_component::XKB_STATE_MODS_LATCHED),
+            locked: mods(xkb_state_component::XKB_STATE_MODS_LOCKED),
+            // SAFETY: `self.state` is live
+            group: unsafe {
+                (library.xkb_state_serialize_layout)(
+                    self.state,
+                    xkb_state_component::XKB_STATE_LAYOUT_EFFECTIVE,
+                )
+            },
+        }
+    }
+}
+
+impl Drop for KeyState {
+    fn drop(&mut self) {
+        if let Ok(library) = library() {
+            // SAFETY: releases the reference taken in `new`
+            unsafe { (library.xkb_state_unref)(self.state) };
+        }
+    }
+}
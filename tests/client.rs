@@ -0,0 +1,553 @@
+use std::{
+    os::fd::{AsFd, AsRawFd},
+    time::{Duration, Instant},
+};
+use way_too_far::config::{Config, GlobalPolicy};
+use wayland_client::{
+    Proxy, QueueHandle, WEnum,
+    protocol::{
+        wl_compositor, wl_keyboard, wl_pointer, wl_registry, wl_seat, wl_shm, wl_surface, wl_touch,
+    },
+};
+
+mod common;
+use common::{ClientState, TestCompositor};
+
+#[path = "../examples/simple-client/client.rs"]
+mod simple_client;
+
+const KEY_LEFTSHIFT: u32 = 42;
+const KEY_A: u32 = 30;
+
+#[test]
+fn registry_advertises_globals() {
+    let compositor = TestCompositor::start();
+    let conn = compositor.connect();
+    let mut queue = conn.new_event_queue();
+    let mut state = ClientState::default();
+    conn.display().get_registry(&queue.handle(), ());
+    queue.roundtrip(&mut state).unwrap();
+
+    assert_eq!(state.global("wl_shm").map(|(_, v)| v), Some(1));
+    assert_eq!(state.global("wl_compositor").map(|(_, v)| v), Some(6));
+    assert_eq!(state.global("xdg_wm_base").map(|(_, v)| v), Some(7));
+    assert_eq!(state.global("wl_seat").map(|(_, v)| v), Some(7));
+}
+
+#[test]
+fn globals_are_advertised_after_a_panic_poisons_the_state() {
+    let compositor = TestCompositor::start();
+    std::thread::scope(|scope| {
+        let poisoner = scope.spawn(|| {
+            let _global_state = compositor.global_state().write().unwrap();
+            panic!("poisoning the global state");
+        });
+        assert!(poisoner.join().is_err());
+    });
+    assert!(compositor.global_state().is_poisoned());
+
+    let conn = compositor.connect();
+    let mut queue = conn.new_event_queue();
+    let mut state = ClientState::default();
+    conn.display().get_registry(&queue.handle(), ());
+    queue.roundtrip(&mut state).unwrap();
+
+    assert_eq!(state.global("wl_compositor").map(|(_, v)| v), Some(6));
+}
+
+#[test]
+fn seat_sends_capabilities_and_name_on_bind() {
+    let compositor = TestCompositor::start();
+    let conn = compositor.connect();
+    let mut queue = conn.new_event_queue();
+    let qh = queue.handle();
+    let mut state = ClientState::default();
+    let registry = conn.display().get_registry(&qh, ());
+    queue.roundtrip(&mut state).unwrap();
+
+    let (name, _) = state.global("wl_seat").unwrap();
+    registry.bind::<wl_seat::WlSeat, _, _>(name, 7, &qh, ());
+    queue.roundtrip(&mut state).unwrap();
+
+    assert_eq!(
+        state.seat_capabilities,
+        Some(wl_seat::Capability::Pointer | wl_seat::Capability::Keyboard)
+    );
+    assert_eq!(state.seat_name.as_deref(), Some("seat0"));
+}
+
+#[test]
+fn pointer_focus_follows_the_surface_under_it() {
+    let compositor = TestCompositor::start();
+    let conn = compositor.connect();
+    let mut queue = conn.new_event_queue();
+    let qh = queue.handle();
+    let mut state = ClientState::default();
+    let registry = conn.display().get_registry(&qh, ());
+    queue.roundtrip(&mut state).unwrap();
+
+    let (seat, surface) = map_surface(&registry, &state, &qh);
+    seat.get_pointer(&qh, ());
+    // The pointer starts at the origin, so mapping the surface enters it
+    queue.roundtrip(&mut state).unwrap();
+
+    let global_state = || compositor.global_state().write().unwrap();
+    global_state().pointer_motion(10.0, 20.0);
+    global_state().pointer_button(272, true);
+    // Held buttons keep focus on the surface the drag started on
+    global_state().pointer_motion(100.0, 100.0);
+    global_state().pointer_button(272, false);
+    queue.roundtrip(&mut state).unwrap();
+    queue.roundtrip(&mut state).unwrap();
+
+    let events = state
+        .pointer_events
+        .iter()
+        .filter(|event| !matches!(event, wl_pointer::Event::Frame))
+        .collect::<Vec<_>>();
+    assert!(
+        matches!(
+            events[..],
+            [
+                wl_pointer::Event::Enter {
+                    surface: entered,
+                    surface_x: 0.0,
+                    surface_y: 0.0,
+                    ..
+                },
+                wl_pointer::Event::Motion {
+                    surface_x: 10.0,
+                    surface_y: 20.0,
+                    ..
+                },
+                wl_pointer::Event::Button { button: 272, .. },
+                wl_pointer::Event::Motion { surface_x: 100.0, surface_y: 100.0, .. },
+                wl_pointer::Event::Button { button: 272, .. },
+                wl_pointer::Event::Leave { surface: left, .. },
+            ] if entered.id() == surface.id() && left.id() == surface.id()
+        ),
+        "unexpected pointer events {:?}",
+        events
+    );
+}
+
+#[test]
+fn keyboard_gets_keymap_focus_and_keys() {
+    let compositor = TestCompositor::start();
+    let conn = compositor.connect();
+    let mut queue = conn.new_event_queue();
+    let qh = queue.handle();
+    let mut state = ClientState::default();
+    let registry = conn.display().get_registry(&qh, ());
+    queue.roundtrip(&mut state).unwrap();
+
+    // The only mapped surface takes keyboard focus
+    let (seat, surface) = map_surface(&registry, &state, &qh);
+    seat.get_keyboard(&qh, ());
+    queue.roundtrip(&mut state).unwrap();
+
+    let global_state = || compositor.global_state().write().unwrap();
+    global_state().keyboard_key(KEY_LEFTSHIFT, true);
+    global_state().keyboard_key(KEY_A, true);
+    global_state().keyboard_key(KEY_A, false);
+    global_state().keyboard_key(KEY_LEFTSHIFT, false);
+    queue.roundtrip(&mut state).unwrap();
+    queue.roundtrip(&mut state).unwrap();
+
+    let mut events = state.keyboard_events.drain(..);
+    let Some(wl_keyboard::Event::Keymap {
+        format: WEnum::Value(wl_keyboard::KeymapFormat::XkbV1),
+        fd,
+        size,
+    }) = events.next()
+    else {
+        panic!("no keymap sent first");
+    };
+    // Version 7 requires a private mapping
+    let keymap = unsafe {
+        memmap2::MmapOptions::new()
+            .len(size as usize)
+            .map_copy_read_only(&std::fs::File::from(fd))
+            .unwrap()
+    };
+    assert!(keymap.starts_with(b"xkb_keymap {"));
+    assert_eq!(keymap.last(), Some(&0));
+
+    let events = events.map(keyboard_event_summary).collect::<Vec<_>>();
+    assert_eq!(
+        events,
+        [
+            "repeat_info 0 600".to_string(),
+            format!("enter {} []", surface.id().protocol_id()),
+            "modifiers 0".to_string(),
+            format!("key {} Pressed", KEY_LEFTSHIFT),
+            "modifiers 1".to_string(),
+            format!("key {} Pressed", KEY_A),
+            format!("key {} Released", KEY_A),
+            format!("key {} Released", KEY_LEFTSHIFT),
+            "modifiers 0".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn only_keyboards_from_version_4_get_repeated_keys() {
+    let mut config = Config::default();
+    config.keyboard.repeat_delay = 50;
+    config.keyboard.repeat_rate = 100;
+    let compositor = TestCompositor::start_with_config(config);
+    let conn = compositor.connect();
+    let mut queue = conn.new_event_queue();
+    let qh = queue.handle();
+    let mut state = ClientState::default();
+    let registry = conn.display().get_registry(&qh, ());
+    queue.roundtrip(&mut state).unwrap();
+    let (seat, _surface) = map_surface(&registry, &state, &qh);
+    let legacy_seat =
+        registry.bind::<wl_seat::WlSeat, _, _>(state.global("wl_seat").unwrap().0, 3, &qh, ());
+    seat.get_keyboard(&qh, ());
+    legacy_seat.get_keyboard(&qh, ());
+    queue.roundtrip(&mut state).unwrap();
+
+    let global_state = || compositor.global_state().write().unwrap();
+    global_state().keyboard_key(KEY_LEFTSHIFT, true);
+    global_state().keyboard_key(KEY_A, true);
+    let presses = |events: &[wl_keyboard::Event]| {
+        events
+            .iter()
+            .filter(|event| {
+                matches!(
+                    event,
+                    wl_keyboard::Event::Key {
+                        key: KEY_A,
+                        state: WEnum::Value(wl_keyboard::KeyState::Pressed),
+                        ..
+                    }
+                )
+            })
+            .count()
+    };
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while presses(&state.keyboard_events) < 3 {
+        assert!(Instant::now() < deadline, "held key never repeated");
+        queue.roundtrip(&mut state).unwrap();
+    }
+    global_state().keyboard_key(KEY_A, false);
+    // Modifiers don't repeat, and neither does anything after release
+    global_state().keyboard_key(KEY_LEFTSHIFT, false);
+    queue.roundtrip(&mut state).unwrap();
+    queue.roundtrip(&mut state).unwrap();
+
+    let summary = |events: &mut Vec<wl_keyboard::Event>| {
+        events
+            .drain(..)
+            .map(keyboard_event_summary)
+            .filter(|event| event.starts_with("key ") || event.starts_with("repeat_info"))
+            .collect::<Vec<_>>()
+    };
+    let events = summary(&mut state.keyboard_events);
+    let press_a = format!("key {} Pressed", KEY_A);
+    assert_eq!(
+        events[..2],
+        [
+            "repeat_info 0 50".to_string(),
+            format!("key {} Pressed", KEY_LEFTSHIFT)
+        ]
+    );
+    assert!(
+        events[2..events.len() - 2]
+            .iter()
+            .all(|event| *event == press_a),
+        "unexpected events in {:?}",
+        events
+    );
+    assert_eq!(
+        events[events.len() - 2..],
+        [
+            format!("key {} Released", KEY_A),
+            format!("key {} Released", KEY_LEFTSHIFT),
+        ]
+    );
+    // Older keyboards repeat on their own, so they get each key only once
+    assert_eq!(
+        summary(&mut state.legacy_keyboard_events),
+        [
+            format!("key {} Pressed", KEY_LEFTSHIFT),
+            format!("key {} Pressed", KEY_A),
+            format!("key {} Released", KEY_A),
+            format!("key {} Released", KEY_LEFTSHIFT),
+        ]
+    );
+}
+
+#[test]
+fn touch_points_go_to_the_surface_they_started_on() {
+    let compositor = TestCompositor::start();
+    let conn = compositor.connect();
+    let mut queue = conn.new_event_queue();
+    let qh = queue.handle();
+    let mut state = ClientState::default();
+    let registry = conn.display().get_registry(&qh, ());
+    queue.roundtrip(&mut state).unwrap();
+    let (seat, _surface) = map_surface(&registry, &state, &qh);
+    queue.roundtrip(&mut state).unwrap();
+    let seat_capabilities = state.seat_capabilities.unwrap();
+    assert!(!seat_capabilities.contains(wl_seat::Capability::Touch));
+
+    let global_state = || compositor.global_state().write().unwrap();
+    global_state().set_touch_present(true);
+    queue.roundtrip(&mut state).unwrap();
+    queue.roundtrip(&mut state).unwrap();
+    assert_eq!(
+        state.seat_capabilities,
+        Some(seat_capabilities | wl_seat::Capability::Touch)
+    );
+
+    seat.get_touch(&qh, ());
+    queue.roundtrip(&mut state).unwrap();
+    global_state().touch_down(0, 5.0, 5.0);
+    // Misses the 64x64 surface
+    global_state().touch_down(1, 100.0, 5.0);
+    // Off the surface, but still sent to it
+    global_state().touch_motion(0, 100.0, 8.0);
+    global_state().touch_up(1);
+    global_state().touch_up(0);
+    global_state().set_touch_present(false);
+    queue.roundtrip(&mut state).unwrap();
+    queue.roundtrip(&mut state).unwrap();
+
+    let events = state
+        .touch_events
+        .iter()
+        .map(|event| match event {
+            wl_touch::Event::Down { id, x, y, .. } => format!("down {} {},{}", id, x, y),
+            wl_touch::Event::Motion { id, x, y, .. } => format!("motion {} {},{}", id, x, y),
+            wl_touch::Event::Up { id, .. } => format!("up {}", id),
+            event => format!("{:?}", event),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        events,
+        [
+            "down 0 5,5",
+            "Frame",
+            "motion 0 100,8",
+            "Frame",
+            "up 0",
+            "Frame"
+        ]
+    );
+    assert_eq!(state.seat_capabilities, Some(seat_capabilities));
+}
+
+#[test]
+fn policy_hides_restricted_globals() {
+    let mut config = Config::default();
+    config
+        .policy
+        .globals
+        .insert("xdg_wm_base".to_string(), GlobalPolicy::default());
+    let compositor = TestCompositor::start_with_config(config);
+    let conn = compositor.connect();
+    let mut queue = conn.new_event_queue();
+    let mut state = ClientState::default();
+    conn.display().get_registry(&queue.handle(), ());
+    queue.roundtrip(&mut state).unwrap();
+
+    assert!(state.global("wl_compositor").is_some());
+    assert!(state.global("xdg_wm_base").is_none());
+}
+
+#[test]
+fn shm_advertises_mandatory_formats() {
+    let compositor = TestCompositor::start();
+    let conn = compositor.connect();
+    let mut queue = conn.new_event_queue();
+    let qh = queue.handle();
+    let mut state = ClientState::default();
+    let registry = conn.display().get_registry(&qh, ());
+    queue.roundtrip(&mut state).unwrap();
+
+    let (name, _) = state.global("wl_shm").unwrap();
+    registry.bind::<wl_shm::WlShm, _, _>(name, 1, &qh, ());
+    queue.roundtrip(&mut state).unwrap();
+
+    assert!(state.shm_formats.contains(&wl_shm::Format::Argb8888));
+    assert!(state.shm_formats.contains(&wl_shm::Format::Xrgb8888));
+}
+
+#[test]
+fn commit_fires_frame_callbacks_and_releases_buffers() {
+    let compositor = TestCompositor::start();
+    // Enough frames that leaked callback objects would show up
+    const FRAMES: usize = 100;
+    let stats = simple_client::run(&compositor.connect(), FRAMES).unwrap();
+
+    assert_eq!(stats.frames_done.len(), FRAMES);
+    assert_eq!(stats.buffers_released, FRAMES);
+}
+
+/// A compact description of a keyboard event.
+fn keyboard_event_summary(event: wl_keyboard::Event) -> String {
+    match event {
+        wl_keyboard::Event::Enter { surface, keys, .. } => {
+            format!("enter {} {:?}", surface.id().protocol_id(), keys)
+        }
+        wl_keyboard::Event::Modifiers { mods_depressed, .. } => {
+            format!("modifiers {}", mods_depressed)
+        }
+        wl_keyboard::Event::Key {
+            key,
+            state: WEnum::Value(state),
+            ..
+        } => format!("key {} {:?}", key, state),
+        wl_keyboard::Event::RepeatInfo { rate, delay } => format!("repeat_info {} {}", rate, delay),
+        event => format!("{:?}", event),
+    }
+}
+
+/// Binds the seat and maps a 64x64 surface at the origin.
+fn map_surface(
+    registry: &wl_registry::WlRegistry,
+    state: &ClientState,
+    qh: &QueueHandle<ClientState>,
+) -> (wl_seat::WlSeat, wl_surface::WlSurface) {
+    let bind = |interface: &str| state.global(interface).unwrap().0;
+    let shm = registry.bind::<wl_shm::WlShm, _, _>(bind("wl_shm"), 1, qh, ());
+    let wl_compositor =
+        registry.bind::<wl_compositor::WlCompositor, _, _>(bind("wl_compositor"), 6, qh, ());
+    let seat = registry.bind::<wl_seat::WlSeat, _, _>(bind("wl_seat"), 7, qh, ());
+    let file = tempfile::tempfile().unwrap();
+    file.set_len(64 * 64 * 4).unwrap();
+    let pool = shm.create_pool(file.as_fd(), 64 * 64 * 4, qh, ());
+    let buffer = pool.create_buffer(0, 64, 64, 64 * 4, wl_shm::Format::Xrgb8888, qh, ());
+    let surface = wl_compositor.create_surface(qh, ());
+    surface.attach(Some(&buffer), 0, 0);
+    surface.commit();
+    (seat, surface)
+}
+
+const XDG_WM_BASE: u32 = 3;
+
+/// Binds xdg_wm_base and maps a surface over the raw wire, which gives it
+/// pointer and keyboard focus.
+fn map_raw_surface(client: &mut common::RawClient) -> std::fs::File {
+    const SHM: u32 = 4;
+    const COMPOSITOR: u32 = 5;
+    const POOL: u32 = 6;
+    const BUFFER: u32 = 7;
+    const SURFACE: u32 = 8;
+    let args = |values: &[u32]| {
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect::<Vec<_>>()
+    };
+    client.bind("xdg_wm_base", 7, XDG_WM_BASE);
+    client.bind("wl_shm", 1, SHM);
+    client.bind("wl_compositor", 6, COMPOSITOR);
+    let file = tempfile::tempfile().unwrap();
+    file.set_len(64 * 64 * 4).unwrap();
+    client.send_with_fds(SHM, 0, &args(&[POOL, 64 * 64 * 4]), &[file.as_raw_fd()]);
+    let xrgb8888 = wl_shm::Format::Xrgb8888 as u32;
+    client.send(POOL, 0, &args(&[BUFFER, 0, 64, 64, 64 * 4, xrgb8888]));
+    client.send(COMPOSITOR, 0, &args(&[SURFACE]));
+    client.send(SURFACE, 1, &args(&[BUFFER, 0, 0]));
+    client.send(SURFACE, 6, &[]);
+    file
+}
+
+/// Reads events up to the next ping, returning its serial.
+fn next_ping(client: &mut common::RawClient) -> Vec<u8> {
+    loop {
+        let (object_id, op_code, serial) = client.next_event();
+        if (object_id, op_code) == (XDG_WM_BASE, 0) {
+            return serial;
+        }
+    }
+}
+
+fn any_client_unresponsive(compositor: &TestCompositor) -> bool {
+    let global_state = compositor.global_state().read().unwrap();
+    global_state
+        .clients
+        .values()
+        .any(|usage| usage.unresponsive())
+}
+
+#[test]
+fn idle_client_is_not_pinged() {
+    let mut config = Config::default();
+    config.ping.timeout_ms = 10;
+    let compositor = TestCompositor::start_with_config(config);
+    let mut client = compositor.connect_raw();
+    client.bind("xdg_wm_base", 7, XDG_WM_BASE);
+    std::thread::sleep(Duration::from_millis(50));
+    // wl_display.sync; everything sent before its done has arrived
+    client.send(1, 0, &10u32.to_le_bytes());
+    loop {
+        let (object_id, op_code, _) = client.next_event();
+        assert_ne!((object_id, op_code), (XDG_WM_BASE, 0), "idle client pinged");
+        if object_id == 10 {
+            break;
+        }
+    }
+    assert!(!any_client_unresponsive(&compositor));
+}
+
+#[test]
+fn client_that_never_pongs_is_reported_unresponsive() {
+    let mut config = Config::default();
+    config.ping.timeout_ms = 20;
+    let compositor = TestCompositor::start_with_config(config);
+    let mut client = compositor.connect_raw();
+    let _pool = map_raw_surface(&mut client);
+    // Gaining focus pings the client
+    next_ping(&mut client);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !any_client_unresponsive(&compositor) {
+        assert!(
+            Instant::now() < deadline,
+            "client never reported unresponsive"
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn client_that_pongs_stays_responsive() {
+    let mut config = Config::default();
+    config.ping.timeout_ms = 100;
+    let compositor = TestCompositor::start_with_config(config);
+    let mut client = compositor.connect_raw();
+    let _pool = map_raw_surface(&mut client);
+    let serial = next_ping(&mut client);
+    client.send(XDG_WM_BASE, 3, &serial);
+
+    // Each click pings again once the last ping was answered
+    let mut serials = vec![serial];
+    for _ in 0..3 {
+        // wl_display.sync, so the pong has been handled before the click
+        client.send(1, 0, &10u32.to_le_bytes());
+        while client.next_event().0 != 10 {}
+        compositor
+            .global_state()
+            .write()
+            .unwrap()
+            .pointer_button(272, true);
+        compositor
+            .global_state()
+            .write()
+            .unwrap()
+            .pointer_button(272, false);
+        let serial = next_ping(&mut client);
+        client.send(XDG_WM_BASE, 3, &serial);
+        assert!(!serials.contains(&serial), "ping serial reused");
+        serials.push(serial);
+    }
+    client.send(1, 0, &10u32.to_le_bytes());
+    while client.next_event().0 != 10 {}
+    std::thread::sleep(Duration::from_millis(150));
+    assert!(!any_client_unresponsive(&compositor));
+}
@@ -0,0 +1,332 @@
+//! Runs the compositor in-process on a private socket for client tests.
+
+// Each test crate uses a different subset of these helpers
+#![allow(dead_code)]
+
+use sendfd::SendWithFd;
+use std::{
+    io::{Read, Write},
+    os::{fd::RawFd, unix::net::UnixStream},
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tempfile::TempDir;
+use tokio::{net::UnixListener, runtime::Runtime};
+use way_too_far::{CompositorGlobalState, config::Config, serve};
+use wayland_client::{
+    Connection, Dispatch, Proxy, QueueHandle, delegate_noop,
+    protocol::{
+        wl_buffer, wl_compositor, wl_keyboard, wl_pointer, wl_registry, wl_seat, wl_shm,
+        wl_shm_pool, wl_surface, wl_touch,
+    },
+};
+
+/// A compositor serving a socket in its own temporary directory.
+///
+/// No outputs or input devices exist; tests drive input through
+/// [`TestCompositor::global_state`]. Dropping it shuts the runtime down and
+/// removes the socket.
+pub struct TestCompositor {
+    socket_path: PathBuf,
+    global_state: Arc<RwLock<CompositorGlobalState>>,
+    _runtime: Runtime,
+    _dir: TempDir,
+}
+
+impl TestCompositor {
+    pub fn start() -> Self {
+        Self::start_with_config(Config::default())
+    }
+
+    pub fn start_with_config(config: Config) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("wayland-test");
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let listener = {
+            let _guard = runtime.enter();
+            UnixListener::bind(&socket_path).unwrap()
+        };
+        let global_state = Arc::new(RwLock::new(CompositorGlobalState::new(config)));
+        runtime.spawn(serve(listener, global_state.clone()));
+        TestCompositor {
+            socket_path,
+            global_state,
+            _runtime: runtime,
+            _dir: dir,
+        }
+    }
+
+    pub fn global_state(&self) -> &RwLock<CompositorGlobalState> {
+        &self.global_state
+    }
+
+    pub fn connect(&self) -> Connection {
+        let stream = UnixStream::connect(&self.socket_path).unwrap();
+        Connection::from_socket(stream).unwrap()
+    }
+
+    pub fn connect_raw(&self) -> RawClient {
+        let stream = UnixStream::connect(&self.socket_path).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        RawClient {
+            stream,
+            has_registry: false,
+        }
+    }
+}
+
+/// Speaks the wire format directly, for requests no well-behaved client
+/// library would send.
+pub struct RawClient {
+    stream: UnixStream,
+    has_registry: bool,
+}
+
+/// An event as `(object_id, opcode, argument bytes)`.
+pub type RawEvent = (u32, u16, Vec<u8>);
+
+impl RawClient {
+    pub fn send(&mut self, object_id: u32, op_code: u16, args: &[u8]) {
+        self.send_with_fds(object_id, op_code, args, &[]);
+    }
+
+    pub fn send_with_fds(&mut self, object_id: u32, op_code: u16, args: &[u8], fds: &[RawFd]) {
+        let mut message = Vec::with_capacity(8 + args.len());
+        message.extend_from_slice(&object_id.to_le_bytes());
+        message.extend_from_slice(&op_code.to_le_bytes());
+        message.extend_from_slice(&(8 + args.len() as u16).to_le_bytes());
+        message.extend_from_slice(args);
+        if fds.is_empty() {
+            self.stream.write_all(&message).unwrap();
+        } else {
+            self.stream.send_with_fd(&message, fds).unwrap();
+        }
+    }
+
+    /// Sends raw bytes, which need not form a valid message.
+    pub fn send_bytes(&mut self, bytes: &[u8]) {
+        self.stream.write_all(bytes).unwrap();
+    }
+
+    /// Binds the global advertising `interface` to `new_id`, using a
+    /// registry created as object 2 on first use.
+    pub fn bind(&mut self, interface: &str, version: u32, new_id: u32) {
+        if !self.has_registry {
+            self.send(1, 1, &2u32.to_le_bytes());
+            self.has_registry = true;
+        }
+        let name = match interface {
+            "wl_shm" => 1u32,
+            "wl_compositor" => 2,
+            "xdg_wm_base" => 3,
+            "wl_seat" => 4,
+            _ => panic!("no global for {}", interface),
+        };
+        let mut args = name.to_le_bytes().to_vec();
+        args.extend_from_slice(&string_arg(interface));
+        args.extend_from_slice(&version.to_le_bytes());
+        args.extend_from_slice(&new_id.to_le_bytes());
+        self.send(2, 0, &args);
+    }
+
+    /// Reads the next event, panicking if none arrives within the read
+    /// timeout.
+    pub fn next_event(&mut self) -> RawEvent {
+        let mut header = [0; 8];
+        self.stream.read_exact(&mut header).unwrap();
+        let object_id = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let op_code = u16::from_le_bytes(header[4..6].try_into().unwrap());
+        let length = u16::from_le_bytes(header[6..8].try_into().unwrap()) as usize;
+        let mut args = vec![0; length - 8];
+        self.stream.read_exact(&mut args).unwrap();
+        (object_id, op_code, args)
+    }
+
+    /// Reads events until the compositor closes the connection.
+    ///
+    /// Panics if it stays open past the read timeout, so a compositor that
+    /// hangs instead of disconnecting fails the test.
+    pub fn events_until_closed(&mut self) -> Vec<RawEvent> {
+        let mut data = Vec::new();
+        let mut chunk = [0; 4096];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(read) => data.extend_from_slice(&chunk[..read]),
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset => break,
+                Err(e) => panic!("compositor did not close the connection: {}", e),
+            }
+        }
+
+        let mut events = Vec::new();
+        let mut rest = &data[..];
+        while rest.len() >= 8 {
+            let object_id = u32::from_le_bytes(rest[0..4].try_into().unwrap());
+            let op_code = u16::from_le_bytes(rest[4..6].try_into().unwrap());
+            let length = u16::from_le_bytes(rest[6..8].try_into().unwrap()) as usize;
+            events.push((object_id, op_code, rest[8..length].to_vec()));
+            rest = &rest[length..];
+        }
+        events
+    }
+
+    /// The `(object_id, code)` of the wl_display.error sent before the
+    /// compositor disconnected.
+    pub fn expect_error(&mut self) -> (u32, u32) {
+        let events = self.events_until_closed();
+        let (_, _, args) = events
+            .iter()
+            .find(|(object_id, op_code, _)| *object_id == 1 && *op_code == 0)
+            .unwrap_or_else(|| panic!("no wl_display.error in {:?}", events));
+        (
+            u32::from_le_bytes(args[0..4].try_into().unwrap()),
+            u32::from_le_bytes(args[4..8].try_into().unwrap()),
+        )
+    }
+}
+
+/// Encodes a wire-format string argument.
+pub fn string_arg(value: &str) -> Vec<u8> {
+    let mut bytes = ((value.len() + 1) as u32).to_le_bytes().to_vec();
+    bytes.extend_from_slice(value.as_bytes());
+    bytes.push(0);
+    bytes.resize(bytes.len().next_multiple_of(4), 0);
+    bytes
+}
+
+/// Client-side record of the events a test cares about.
+#[derive(Default)]
+pub struct ClientState {
+    /// `(name, interface, version)` of every advertised global.
+    pub globals: Vec<(u32, String, u32)>,
+    pub shm_formats: Vec<wl_shm::Format>,
+    pub seat_capabilities: Option<wl_seat::Capability>,
+    pub seat_name: Option<String>,
+    pub pointer_events: Vec<wl_pointer::Event>,
+    pub keyboard_events: Vec<wl_keyboard::Event>,
+    /// Events of keyboards below version 4, which repeat keys themselves.
+    pub legacy_keyboard_events: Vec<wl_keyboard::Event>,
+    pub touch_events: Vec<wl_touch::Event>,
+}
+
+impl ClientState {
+    /// The advertised name and version of `interface`, if any.
+    pub fn global(&self, interface: &str) -> Option<(u32, u32)> {
+        self.globals
+            .iter()
+            .find(|(_, global, _)| global == interface)
+            .map(|(name, _, version)| (*name, *version))
+    }
+}
+
+impl Dispatch<wl_registry::WlRegistry, ()> for ClientState {
+    fn event(
+        state: &mut Self,
+        _registry: &wl_registry::WlRegistry,
+        event: wl_registry::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if let wl_registry::Event::Global {
+            name,
+            interface,
+            version,
+        } = event
+        {
+            state.globals.push((name, interface, version));
+        }
+    }
+}
+
+impl Dispatch<wl_shm::WlShm, ()> for ClientState {
+    fn event(
+        state: &mut Self,
+        _shm: &wl_shm::WlShm,
+        event: wl_shm::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if let wl_shm::Event::Format {
+            format: wayland_client::WEnum::Value(format),
+        } = event
+        {
+            state.shm_formats.push(format);
+        }
+    }
+}
+
+impl Dispatch<wl_seat::WlSeat, ()> for ClientState {
+    fn event(
+        state: &mut Self,
+        _seat: &wl_seat::WlSeat,
+        event: wl_seat::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            wl_seat::Event::Capabilities {
+                capabilities: wayland_client::WEnum::Value(capabilities),
+            } => state.seat_capabilities = Some(capabilities),
+            wl_seat::Event::Name { name } => state.seat_name = Some(name),
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<wl_pointer::WlPointer, ()> for ClientState {
+    fn event(
+        state: &mut Self,
+        _pointer: &wl_pointer::WlPointer,
+        event: wl_pointer::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        state.pointer_events.push(event);
+    }
+}
+
+impl Dispatch<wl_keyboard::WlKeyboard, ()> for ClientState {
+    fn event(
+        state: &mut Self,
+        keyboard: &wl_keyboard::WlKeyboard,
+        event: wl_keyboard::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if keyboard.version() < 4 {
+            state.legacy_keyboard_events.push(event);
+        } else {
+            state.keyboard_events.push(event);
+        }
+    }
+}
+
+impl Dispatch<wl_touch::WlTouch, ()> for ClientState {
+    fn event(
+        state: &mut Self,
+        _touch: &wl_touch::WlTouch,
+        event: wl_touch::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        state.touch_events.push(event);
+    }
+}
+
+delegate_noop!(ClientState: ignore wl_compositor::WlCompositor);
+delegate_noop!(ClientState: ignore wl_shm_pool::WlShmPool);
+delegate_noop!(ClientState: ignore wl_buffer::WlBuffer);
+delegate_noop!(ClientState: ignore wl_surface::WlSurface);
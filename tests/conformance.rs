@@ -0,0 +1,344 @@
+//! Requests that break the protocol must end in the matching
+//! wl_display.error and a disconnect, never a panic or a hang.
+
+use std::os::fd::AsRawFd;
+
+mod common;
+use common::TestCompositor;
+use way_too_far::config::{Config, GlobalPolicy};
+
+const INVALID_OBJECT: u32 = 0;
+const INVALID_METHOD: u32 = 1;
+
+const SHM_INVALID_FORMAT: u32 = 0;
+const SHM_INVALID_STRIDE: u32 = 1;
+
+const SEAT_MISSING_CAPABILITY: u32 = 0;
+
+const SURFACE_INVALID_SCALE: u32 = 0;
+const SURFACE_INVALID_TRANSFORM: u32 = 1;
+
+const SHM: u32 = 3;
+const COMPOSITOR: u32 = 4;
+const POOL: u32 = 5;
+const SURFACE: u32 = 6;
+const SEAT: u32 = 7;
+
+const POOL_SIZE: i32 = 4096;
+
+fn args(values: &[u32]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+}
+
+/// Connects, binds wl_shm and wl_compositor and creates a pool.
+fn client_with_pool(compositor: &TestCompositor) -> (common::RawClient, std::fs::File) {
+    let mut client = compositor.connect_raw();
+    client.bind("wl_shm", 1, SHM);
+    client.bind("wl_compositor", 4, COMPOSITOR);
+    let file = tempfile::tempfile().unwrap();
+    file.set_len(POOL_SIZE as u64).unwrap();
+    client.send_with_fds(
+        SHM,
+        0,
+        &args(&[POOL, POOL_SIZE as u32]),
+        &[file.as_raw_fd()],
+    );
+    (client, file)
+}
+
+#[test]
+fn unknown_opcode_is_invalid_method() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.send(1, 7, &[]);
+    assert_eq!(client.expect_error(), (1, INVALID_METHOD));
+}
+
+#[test]
+fn unknown_opcode_on_bound_global_is_invalid_method() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.bind("wl_compositor", 4, COMPOSITOR);
+    client.send(COMPOSITOR, 2, &args(&[10]));
+    assert_eq!(client.expect_error(), (COMPOSITOR, INVALID_METHOD));
+}
+
+#[test]
+fn request_on_object_without_requests_is_invalid_method() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    // wl_display.sync creates a callback that is destroyed once done is
+    // sent, so use a frame callback that hasn't fired yet
+    client.bind("wl_compositor", 4, COMPOSITOR);
+    client.send(COMPOSITOR, 0, &args(&[SURFACE]));
+    client.send(SURFACE, 3, &args(&[10]));
+    client.send(10, 0, &[]);
+    assert_eq!(client.expect_error(), (10, INVALID_METHOD));
+}
+
+#[test]
+fn reused_new_id_is_rejected() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.bind("wl_compositor", 4, COMPOSITOR);
+    client.send(COMPOSITOR, 0, &args(&[COMPOSITOR]));
+    assert_eq!(client.expect_error(), (COMPOSITOR, INVALID_METHOD));
+}
+
+#[test]
+fn new_id_in_server_range_is_rejected() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.bind("wl_compositor", 4, COMPOSITOR);
+    client.send(COMPOSITOR, 0, &args(&[0xff00_0001]));
+    assert_eq!(client.expect_error(), (COMPOSITOR, INVALID_METHOD));
+}
+
+#[test]
+fn attaching_a_non_buffer_is_rejected() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.bind("wl_compositor", 4, COMPOSITOR);
+    client.send(COMPOSITOR, 0, &args(&[SURFACE]));
+    client.send(SURFACE, 1, &args(&[COMPOSITOR, 0, 0]));
+    assert_eq!(client.expect_error(), (SURFACE, INVALID_METHOD));
+}
+
+#[test]
+fn attaching_an_unknown_buffer_is_rejected() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.bind("wl_compositor", 4, COMPOSITOR);
+    client.send(COMPOSITOR, 0, &args(&[SURFACE]));
+    client.send(SURFACE, 1, &args(&[99, 0, 0]));
+    assert_eq!(client.expect_error(), (SURFACE, INVALID_METHOD));
+}
+
+#[test]
+fn setting_a_non_region_as_input_region_is_rejected() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.bind("wl_compositor", 4, COMPOSITOR);
+    client.send(COMPOSITOR, 0, &args(&[SURFACE]));
+    client.send(SURFACE, 5, &args(&[SURFACE]));
+    assert_eq!(client.expect_error(), (SURFACE, INVALID_METHOD));
+}
+
+#[test]
+fn unknown_buffer_transform_is_invalid_transform() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.bind("wl_compositor", 4, COMPOSITOR);
+    client.send(COMPOSITOR, 0, &args(&[SURFACE]));
+    client.send(SURFACE, 7, &args(&[8]));
+    assert_eq!(client.expect_error(), (SURFACE, SURFACE_INVALID_TRANSFORM));
+}
+
+#[test]
+fn negative_buffer_transform_is_invalid_transform() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.bind("wl_compositor", 4, COMPOSITOR);
+    client.send(COMPOSITOR, 0, &args(&[SURFACE]));
+    client.send(SURFACE, 7, &args(&[(-1i32) as u32]));
+    assert_eq!(client.expect_error(), (SURFACE, SURFACE_INVALID_TRANSFORM));
+}
+
+#[test]
+fn zero_buffer_scale_is_invalid_scale() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.bind("wl_compositor", 4, COMPOSITOR);
+    client.send(COMPOSITOR, 0, &args(&[SURFACE]));
+    client.send(SURFACE, 8, &args(&[0]));
+    assert_eq!(client.expect_error(), (SURFACE, SURFACE_INVALID_SCALE));
+}
+
+#[test]
+fn negative_buffer_scale_is_invalid_scale() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.bind("wl_compositor", 4, COMPOSITOR);
+    client.send(COMPOSITOR, 0, &args(&[SURFACE]));
+    client.send(SURFACE, 8, &args(&[(-2i32) as u32]));
+    assert_eq!(client.expect_error(), (SURFACE, SURFACE_INVALID_SCALE));
+}
+
+#[test]
+fn empty_pool_is_invalid_stride() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.bind("wl_shm", 1, SHM);
+    let file = tempfile::tempfile().unwrap();
+    client.send_with_fds(SHM, 0, &args(&[POOL, 0]), &[file.as_raw_fd()]);
+    assert_eq!(client.expect_error(), (SHM, SHM_INVALID_STRIDE));
+}
+
+#[test]
+fn negative_pool_size_is_invalid_stride() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.bind("wl_shm", 1, SHM);
+    let file = tempfile::tempfile().unwrap();
+    client.send_with_fds(
+        SHM,
+        0,
+        &args(&[POOL, (-4096i32) as u32]),
+        &[file.as_raw_fd()],
+    );
+    assert_eq!(client.expect_error(), (SHM, SHM_INVALID_STRIDE));
+}
+
+#[test]
+fn pool_without_fd_is_invalid_method() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.bind("wl_shm", 1, SHM);
+    client.send(SHM, 0, &args(&[POOL, POOL_SIZE as u32]));
+    assert_eq!(client.expect_error(), (SHM, INVALID_METHOD));
+}
+
+#[test]
+fn buffer_past_pool_end_is_invalid_stride() {
+    let compositor = TestCompositor::start();
+    let (mut client, _file) = client_with_pool(&compositor);
+    // 64 rows of 256 bytes need 16 KiB from a 4 KiB pool
+    client.send(POOL, 0, &args(&[7, 0, 64, 64, 256, 0]));
+    assert_eq!(client.expect_error(), (POOL, SHM_INVALID_STRIDE));
+}
+
+#[test]
+fn stride_narrower_than_width_is_invalid_stride() {
+    let compositor = TestCompositor::start();
+    let (mut client, _file) = client_with_pool(&compositor);
+    client.send(POOL, 0, &args(&[7, 0, 16, 4, 16, 0]));
+    assert_eq!(client.expect_error(), (POOL, SHM_INVALID_STRIDE));
+}
+
+#[test]
+fn negative_offset_is_invalid_stride() {
+    let compositor = TestCompositor::start();
+    let (mut client, _file) = client_with_pool(&compositor);
+    client.send(POOL, 0, &args(&[7, (-4i32) as u32, 4, 4, 16, 0]));
+    assert_eq!(client.expect_error(), (POOL, SHM_INVALID_STRIDE));
+}
+
+#[test]
+fn committing_a_destroyed_buffer_attaches_none() {
+    let compositor = TestCompositor::start();
+    let (mut client, _file) = client_with_pool(&compositor);
+    client.send(POOL, 0, &args(&[7, 0, 4, 4, 16, 1]));
+    client.send(COMPOSITOR, 0, &args(&[SURFACE]));
+    client.send(SURFACE, 1, &args(&[7, 0, 0]));
+    client.send(7, 0, &[]);
+    client.send(SURFACE, 6, &[]);
+    // Still connected, so the next bad request is the one reported
+    client.send(1, 7, &[]);
+    assert_eq!(client.expect_error(), (1, INVALID_METHOD));
+}
+
+#[test]
+fn unsupported_format_is_invalid_format() {
+    let compositor = TestCompositor::start();
+    let (mut client, _file) = client_with_pool(&compositor);
+    client.send(POOL, 0, &args(&[7, 0, 4, 4, 16, 0x3231_5659]));
+    assert_eq!(client.expect_error(), (POOL, SHM_INVALID_FORMAT));
+}
+
+#[test]
+fn truncated_arguments_are_invalid_method() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.send(1, 1, &[]);
+    assert_eq!(client.expect_error(), (1, INVALID_METHOD));
+}
+
+#[test]
+fn string_without_terminator_is_invalid_method() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.send(1, 1, &args(&[2]));
+    let mut bind = args(&[1, 4]);
+    bind.extend_from_slice(b"wl_s");
+    bind.extend_from_slice(&args(&[1, 3]));
+    client.send(2, 0, &bind);
+    assert_eq!(client.expect_error(), (2, INVALID_METHOD));
+}
+
+#[test]
+fn binding_above_the_advertised_version_is_invalid_object() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.bind("wl_compositor", 7, COMPOSITOR);
+    assert_eq!(client.expect_error(), (2, INVALID_OBJECT));
+}
+
+#[test]
+fn binding_an_unknown_global_is_invalid_object() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.send(1, 1, &args(&[2]));
+    let mut bind = args(&[99]);
+    bind.extend_from_slice(&common::string_arg("wl_shm"));
+    bind.extend_from_slice(&args(&[1, SHM]));
+    client.send(2, 0, &bind);
+    assert_eq!(client.expect_error(), (2, INVALID_OBJECT));
+}
+
+#[test]
+fn binding_a_global_as_another_interface_is_invalid_object() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.send(1, 1, &args(&[2]));
+    // Name 1 is wl_shm
+    let mut bind = args(&[1]);
+    bind.extend_from_slice(&common::string_arg("wl_compositor"));
+    bind.extend_from_slice(&args(&[4, COMPOSITOR]));
+    client.send(2, 0, &bind);
+    assert_eq!(client.expect_error(), (2, INVALID_OBJECT));
+}
+
+#[test]
+fn binding_a_global_hidden_by_policy_is_invalid_object() {
+    let mut config = Config::default();
+    config
+        .policy
+        .globals
+        .insert("xdg_wm_base".to_string(), GlobalPolicy::default());
+    let compositor = TestCompositor::start_with_config(config);
+    let mut client = compositor.connect_raw();
+    client.bind("xdg_wm_base", 1, 8);
+    assert_eq!(client.expect_error(), (2, INVALID_OBJECT));
+}
+
+#[test]
+fn device_for_a_missing_capability_is_rejected() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.bind("wl_seat", 7, SEAT);
+    // get_touch; the seat has never had a touch device
+    client.send(SEAT, 2, &args(&[10]));
+    assert_eq!(client.expect_error(), (SEAT, SEAT_MISSING_CAPABILITY));
+}
+
+#[test]
+fn message_shorter_than_header_disconnects() {
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.send_bytes(&[1, 0, 0, 0, 0, 0, 4, 0]);
+    assert!(client.events_until_closed().is_empty());
+}
+
+#[test]
+fn request_to_unknown_object_does_not_disconnect() {
+    // Unknown targets only raise the misbehavior score (see throttle.rs),
+    // since a client may race a server-side destroy
+    let compositor = TestCompositor::start();
+    let mut client = compositor.connect_raw();
+    client.send(42, 0, &[]);
+    client.send(1, 7, &[]);
+    assert_eq!(client.expect_error(), (1, INVALID_METHOD));
+}